@@ -1,17 +1,449 @@
 use crate::providers::{GithubProvider, GitlabProvider, Provider};
-use crate::repository::Repository;
-use anyhow::Context;
+use crate::repository::{CloneFilter, CloneOptions, Repository};
+use anyhow::{anyhow, bail, Context};
+use console::style;
+use schemars::schema::{RootSchema, Schema};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ffi::OsString;
 use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use walkdir::WalkDir;
 
-#[derive(Deserialize, Serialize, Debug)]
-struct ConfigContents {
+/// The combined contents of all configuration files in the workspace.
+#[derive(Debug, Default)]
+pub struct ConfigContents {
+    /// What to do when two different repositories would be cloned to the same path
+    pub on_collision: Option<CollisionStrategy>,
+    /// Settings that are applied to every provider that does not set them
+    pub defaults: toml::Table,
+    pub providers: Vec<ProviderSource>,
+    pub overrides: Vec<RepositoryOverride>,
+    pub groups: Vec<RepositoryGroup>,
+}
+
+impl ConfigContents {
+    /// Find the group called `name`.
+    pub fn group(&self, name: &str) -> anyhow::Result<&RepositoryGroup> {
+        match self.groups.iter().find(|group| group.name == name) {
+            Some(group) => Ok(group),
+            None if self.groups.is_empty() => {
+                bail!("Unknown group {}, no groups are configured", name)
+            }
+            None => bail!(
+                "Unknown group {}, expected one of {}",
+                name,
+                self.groups
+                    .iter()
+                    .map(|group| group.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Settings applied to every repository whose path matches `regex` or `glob`, on top of what
+/// the provider returns.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryOverride {
+    /// Apply to repositories whose path matches this regular expression
+    regex: Option<String>,
+    /// Apply to repositories whose path matches this glob
+    glob: Option<String>,
+    /// Track this branch instead of the repository's default branch
+    branch: Option<String>,
+    /// Create a shallow clone with this many commits
+    depth: Option<u32>,
+    /// Create a partial clone: "blob:none" downloads the contents of files when they are
+    /// needed, and "tree:0" does the same for directories
+    filter: Option<CloneFilter>,
+    /// Only fetch the branch that is checked out
+    single_branch: Option<bool>,
+    /// Don't fetch the repository with `git workspace fetch`
+    #[serde(default)]
+    skip_fetch: bool,
+    /// Only check out these directories, with a cone mode sparse checkout. Run
+    /// `git workspace sparse sync` to apply changes to repositories that are already cloned.
+    sparse: Option<Vec<String>>,
+    /// Clone the repository to this path instead
+    path: Option<String>,
+}
+
+/// Matches repository paths against a regular expression or a glob.
+pub enum PathMatcher {
+    Regex(regex::Regex),
+    Glob(globset::GlobMatcher),
+}
+
+impl PathMatcher {
+    pub fn new(regex: &Option<String>, glob: &Option<String>) -> anyhow::Result<PathMatcher> {
+        match (regex, glob) {
+            (Some(regex), None) => Ok(PathMatcher::Regex(
+                regex::Regex::new(regex)
+                    .with_context(|| format!("Invalid regular expression {}", regex))?,
+            )),
+            (None, Some(glob)) => Ok(PathMatcher::Glob(
+                globset::GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid glob {}", glob))?
+                    .compile_matcher(),
+            )),
+            _ => bail!("Exactly one of regex or glob must be given"),
+        }
+    }
+
+    pub fn is_match(&self, repository: &Repository) -> bool {
+        let path = slash_path(repository);
+        match self {
+            PathMatcher::Regex(regex) => regex.is_match(&path),
+            PathMatcher::Glob(glob) => glob.is_match(&path),
+        }
+    }
+}
+
+// Paths are normalized to the platform separator, but patterns are written with `/`.
+fn slash_path(repository: &Repository) -> String {
+    repository.name().replace(std::path::MAIN_SEPARATOR, "/")
+}
+
+/// A named set of repositories that commands can be limited to with `--group`. A repository is
+/// in the group if it matches any of the criteria.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryGroup {
+    /// The name used to select the group with `--group`
+    pub name: String,
+    /// Repositories from these providers, like `github/my-company`
+    #[serde(default)]
+    providers: Vec<String>,
+    /// Repositories whose path matches any of these globs
+    #[serde(default)]
+    globs: Vec<String>,
+    /// Repositories with any of these topics
+    #[serde(default)]
+    topics: Vec<String>,
+    /// Repositories with exactly these paths
+    #[serde(default)]
+    repositories: Vec<String>,
+}
+
+/// A `RepositoryGroup` with its globs compiled.
+pub struct GroupMatcher<'a> {
+    group: &'a RepositoryGroup,
+    globs: Vec<PathMatcher>,
+}
+
+impl RepositoryGroup {
+    pub fn matcher(&self) -> anyhow::Result<GroupMatcher<'_>> {
+        let globs = self
+            .globs
+            .iter()
+            .map(|glob| PathMatcher::new(&None, &Some(glob.clone())))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("Error in group {}", self.name))?;
+        Ok(GroupMatcher { group: self, globs })
+    }
+}
+
+impl GroupMatcher<'_> {
+    pub fn is_match(&self, repository: &Repository) -> bool {
+        let path = slash_path(repository);
+        let group = self.group;
+        group
+            .repositories
+            .iter()
+            .any(|p| p.trim_end_matches('/') == path)
+            || self.globs.iter().any(|glob| glob.is_match(repository))
+            || repository
+                .topics
+                .iter()
+                .any(|topic| group.topics.iter().any(|t| t.eq_ignore_ascii_case(topic)))
+            || repository.provider.as_deref().is_some_and(|provider| {
+                group
+                    .providers
+                    .iter()
+                    .any(|p| p.eq_ignore_ascii_case(provider))
+            })
+    }
+}
+
+impl RepositoryOverride {
+    pub fn matcher(&self) -> anyhow::Result<PathMatcher> {
+        PathMatcher::new(&self.regex, &self.glob)
+    }
+
+    pub fn apply(&self, repository: &mut Repository) {
+        if let Some(branch) = &self.branch {
+            repository.branch = Some(branch.clone());
+        }
+        if let Some(depth) = self.depth {
+            repository.depth = Some(depth);
+        }
+        if let Some(filter) = self.filter {
+            repository.filter = Some(filter);
+        }
+        if let Some(single_branch) = self.single_branch {
+            repository.single_branch = single_branch;
+        }
+        if self.skip_fetch {
+            repository.skip_fetch = true;
+        }
+        if let Some(sparse) = &self.sparse {
+            repository.sparse = sparse.clone();
+        }
+        if let Some(path) = &self.path {
+            repository.set_path(path.clone());
+        }
+    }
+}
+
+/// A single configuration file, before any defaults have been applied to its providers.
+///
+/// The doc comments on the fields are used as descriptions in the JSON Schema.
+#[derive(Deserialize, JsonSchema)]
+#[schemars(
+    title = "git-workspace configuration",
+    description = "A git-workspace configuration file, like workspace.toml",
+    deny_unknown_fields
+)]
+struct ConfigFile {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    text: String,
+    /// What to do when two different repositories would be cloned to the same path
+    on_collision: Option<CollisionStrategy>,
+    /// Other configuration files to read, relative to this file. Can contain globs.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Settings applied to every provider that does not set them. Settings for a single kind
+    /// of provider can be given in a table named after it, like `[defaults.github]`.
+    #[serde(default)]
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
+    defaults: toml::Table,
+    /// The providers to fetch repositories from
     #[serde(rename = "provider", default)]
-    providers: Vec<ProviderSource>,
+    #[schemars(with = "Vec<ProviderSource>")]
+    providers: Vec<toml::Spanned<toml::Table>>,
+    /// Settings applied to repositories whose path matches a regex or glob
+    #[serde(rename = "override", default)]
+    #[schemars(with = "Vec<RepositoryOverride>")]
+    overrides: Vec<toml::Spanned<RepositoryOverride>>,
+    /// Named sets of repositories that commands can be limited to with `--group`
+    #[serde(rename = "group", default)]
+    #[schemars(with = "Vec<RepositoryGroup>")]
+    groups: Vec<toml::Spanned<RepositoryGroup>>,
+    // Anything else in the file is not a setting we know about
+    #[serde(flatten)]
+    #[schemars(skip)]
+    unknown: toml::Table,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> anyhow::Result<ConfigFile> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read file {}", path.display()))?;
+        Self::parse(path, text)
+    }
+
+    fn parse(path: &Path, text: String) -> anyhow::Result<ConfigFile> {
+        let mut file: ConfigFile = toml::from_str(&text)
+            .with_context(|| format!("Error parsing TOML in file {}", path.display()))?;
+        file.path = path.to_path_buf();
+        file.text = text;
+        file.interpolate_env_vars()?;
+        Ok(file)
+    }
+
+    /// Replace any environment variables used in the defaults and provider settings.
+    fn interpolate_env_vars(&mut self) -> anyhow::Result<()> {
+        for (key, value) in self.defaults.iter_mut() {
+            interpolate_value(value, &format!("defaults.{}", key))
+                .with_context(|| format!("Error in file {}", self.path.display()))?;
+        }
+        let lines: Vec<usize> = self
+            .providers
+            .iter()
+            .map(|provider| self.line_at(provider.span().start))
+            .collect();
+        for (provider, line) in self.providers.iter_mut().zip(lines) {
+            for (key, value) in provider.get_mut().iter_mut() {
+                interpolate_value(value, key).with_context(|| {
+                    format!(
+                        "Error in the provider in {} on line {}",
+                        self.path.display(),
+                        line
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The 1-based line number of a position in the file.
+    fn line_at(&self, position: usize) -> usize {
+        self.text[..position].matches('\n').count() + 1
+    }
+
+    /// Where each top-level setting is in the file, or each setting in `[defaults]`. Tables
+    /// such as `[defaults.github]` span their header and all of their settings.
+    fn key_spans(&self, in_defaults: bool) -> HashMap<String, std::ops::Range<usize>> {
+        type Spans = HashMap<String, toml::Spanned<toml::Value>>;
+        #[derive(Deserialize)]
+        struct Defaults {
+            #[serde(default)]
+            defaults: Spans,
+        }
+        let spans = match in_defaults {
+            true => toml::from_str::<Defaults>(&self.text).map(|file| file.defaults),
+            false => toml::from_str::<Spans>(&self.text),
+        };
+        spans
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, value.span()))
+            .collect()
+    }
+
+    /// Apply `defaults` to a provider table from this file and deserialize it.
+    fn parse_provider(
+        &self,
+        provider: &toml::Spanned<toml::Table>,
+        defaults: &toml::Table,
+    ) -> anyhow::Result<ProviderSource> {
+        let mut table = provider.get_ref().clone();
+        apply_defaults(&mut table, defaults);
+        ProviderSource::deserialize(toml::Value::Table(table)).with_context(|| {
+            format!(
+                "Error parsing the provider in {} on line {}",
+                self.path.display(),
+                self.line_at(provider.span().start)
+            )
+        })
+    }
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in a string with the value of the environment variable
+/// `VAR`. The default is used if the variable is not set or is empty. `$$` is replaced with `$`.
+fn interpolate(text: &str) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('$') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .with_context(|| format!("Missing closing brace in {}", text))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (env::var(name), default) {
+                (Ok(value), Some(default)) if value.is_empty() => result.push_str(default),
+                (Ok(value), _) => result.push_str(&value),
+                (Err(_), Some(default)) => result.push_str(default),
+                (Err(_), None) => bail!("Environment variable {} is not set", name),
+            }
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Interpolate environment variables in every string within `value`. `field` is the name of
+/// the setting, used in error messages.
+fn interpolate_value(value: &mut toml::Value, field: &str) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(text) => {
+            *text = interpolate(text).with_context(|| format!("Error in field {}", field))?
+        }
+        toml::Value::Array(items) => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{}[{}]", field, idx))?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                interpolate_value(item, &format!("{}.{}", field, key))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Add any settings from `other` that are not already in `defaults`. The tables for each kind
+/// of provider, like `[defaults.github]`, are combined in the same way.
+fn merge_defaults(defaults: &mut toml::Table, other: &toml::Table) {
+    for (key, value) in other {
+        match (defaults.get_mut(key), value) {
+            (None, _) => {
+                defaults.insert(key.clone(), value.clone());
+            }
+            (Some(toml::Value::Table(existing)), toml::Value::Table(other)) => {
+                merge_defaults(existing, other)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fill in any settings missing from a provider table from the defaults. Settings for the kind
+/// of provider, like `[defaults.github]`, take priority over those in `[defaults]`. Lists like
+/// `exclude` are combined rather than replaced.
+fn apply_defaults(provider: &mut toml::Table, defaults: &toml::Table) {
+    let kind = provider
+        .get("provider")
+        .and_then(toml::Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let kind_defaults = defaults.get(&kind).and_then(toml::Value::as_table);
+    let general_defaults = defaults.iter().filter(|(_, value)| !value.is_table());
+
+    for (key, value) in kind_defaults.into_iter().flatten().chain(general_defaults) {
+        match (provider.get_mut(key), value) {
+            (None, _) => {
+                provider.insert(key.clone(), value.clone());
+            }
+            (Some(toml::Value::Array(items)), toml::Value::Array(default_items)) => {
+                for item in default_items {
+                    if !items.contains(item) {
+                        items.push(item.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How to resolve two different repositories that would be cloned to the same path.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionStrategy {
+    /// Refuse to write the lockfile
+    #[default]
+    Error,
+    /// Keep the repository from the provider that is configured first
+    FirstWins,
+    /// Keep the repository from the provider that is configured first, and add the host
+    /// as a suffix to the paths of the others
+    SuffixHost,
 }
 
 pub struct Config {
@@ -34,7 +466,414 @@ pub fn all_config_files(workspace: &Path) -> anyhow::Result<Vec<PathBuf>> {
         .collect();
     entries_that_exist.sort();
 
-    Ok(entries_that_exist)
+    let mut all_files = vec![];
+    for path in entries_that_exist {
+        resolve_includes(path, &mut vec![], &mut all_files)?;
+    }
+    Ok(all_files)
+}
+
+/// Add `path` and, recursively, every file it includes to `all_files`. Files that are included
+/// more than once are only added the first time. `stack` holds the chain of files that
+/// included this one, so we can detect cycles.
+fn resolve_includes(
+    path: PathBuf,
+    stack: &mut Vec<PathBuf>,
+    all_files: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let canonical_path =
+        dunce::canonicalize(&path).with_context(|| format!("Cannot resolve {}", path.display()))?;
+    if let Some(idx) = stack.iter().position(|p| p == &canonical_path) {
+        let chain: Vec<String> = stack[idx..]
+            .iter()
+            .chain(std::iter::once(&canonical_path))
+            .map(|p| p.display().to_string())
+            .collect();
+        bail!(
+            "Configuration files include each other: {}",
+            chain.join(" -> ")
+        );
+    }
+    if all_files
+        .iter()
+        .any(|p| dunce::canonicalize(p).ok().as_ref() == Some(&canonical_path))
+    {
+        return Ok(());
+    }
+    all_files.push(path);
+
+    // Errors parsing the file are reported when it is read properly, so we skip them here.
+    let file = match ConfigFile::read(&canonical_path) {
+        Ok(file) => file,
+        Err(_) => return Ok(()),
+    };
+    stack.push(canonical_path.clone());
+    for pattern in &file.include {
+        for included in expand_include(&canonical_path, pattern)? {
+            resolve_includes(included, stack, all_files)?;
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+const GLOB_CHARACTERS: [char; 4] = ['*', '?', '[', '{'];
+
+/// Find the files matching a pattern included by the file `including`, relative to its
+/// directory.
+fn expand_include(including: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let base_directory = including.parent().unwrap_or_else(|| Path::new("."));
+    let full_pattern = base_directory.join(pattern);
+    let is_glob = pattern.contains(GLOB_CHARACTERS);
+    if !is_glob {
+        // The file could be inside a repository that has not been cloned yet, so this is
+        // not an error.
+        if !full_pattern.exists() {
+            eprintln!(
+                "{} included file {} does not exist",
+                style("Warning:").yellow(),
+                full_pattern.display()
+            );
+            return Ok(vec![]);
+        }
+        return Ok(vec![full_pattern]);
+    }
+
+    // Walk from the deepest directory that doesn't contain a glob.
+    let root: PathBuf = full_pattern
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(GLOB_CHARACTERS))
+        .collect();
+    let matcher = globset::GlobBuilder::new(&full_pattern.to_string_lossy())
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid include pattern {}", pattern))?
+        .compile_matcher();
+    let mut matches: Vec<PathBuf> = WalkDir::new(&root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && matcher.is_match(entry.path()))
+        // Like `all_config_files`, a pattern like `*.toml` shouldn't read the lockfile, or
+        // include the file it is in.
+        .filter(|entry| entry.file_name() != "workspace-lock.toml" && entry.path() != including)
+        .map(|entry| entry.into_path())
+        .collect();
+    matches.sort();
+    Ok(matches)
+}
+
+/// A problem found in a configuration file by `check_config_files`.
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file.display(), line, self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+/// Find the 1-based line that `key` is defined on within `span` of `text`.
+fn line_of_key(text: &str, span: std::ops::Range<usize>, key: &str) -> Option<usize> {
+    let start_line = text[..span.start].matches('\n').count();
+    text[span]
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|idx| start_line + idx + 1)
+}
+
+/// The settings each kind of provider accepts, like "github", taken from their JSON Schema.
+fn provider_keys() -> HashMap<String, HashSet<String>> {
+    let schema = schemars::schema_for!(ProviderSource);
+    let variants = schema
+        .schema
+        .subschemas
+        .iter()
+        .flat_map(|s| s.one_of.iter().flatten());
+    let mut keys = HashMap::new();
+    for variant in variants {
+        let properties = match variant {
+            Schema::Object(variant) => match &variant.object {
+                Some(object) => &object.properties,
+                None => continue,
+            },
+            _ => continue,
+        };
+        // The kind is the only value allowed for the `provider` tag
+        let kind = match properties.get("provider") {
+            Some(Schema::Object(tag)) => tag
+                .enum_values
+                .iter()
+                .flatten()
+                .find_map(serde_json::Value::as_str),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            keys.insert(kind.to_string(), properties.keys().cloned().collect());
+        }
+    }
+    keys
+}
+
+/// Keys that are present in `raw` but are not `known`, which serde silently ignores.
+fn unknown_keys<'a>(raw: &'a toml::Table, known: &HashSet<String>) -> Vec<&'a String> {
+    raw.keys().filter(|key| !known.contains(*key)).collect()
+}
+
+/// Check that all configuration files can be parsed and that every provider is valid. This
+/// is stricter than `Config::read`, which ignores unknown keys.
+pub fn check_config_files(files: &[PathBuf]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut report = |file: &Path, line: Option<usize>, message: String| {
+        diagnostics.push(Diagnostic {
+            file: file.to_path_buf(),
+            line,
+            message,
+        })
+    };
+
+    let mut config_files = vec![];
+    for path in files {
+        // The TOML errors include the line and column.
+        match ConfigFile::read(path) {
+            Ok(file) => config_files.push(file),
+            Err(e) => report(path, None, format!("{:#}", e).trim_end().to_string()),
+        }
+    }
+
+    let mut defaults = toml::Table::new();
+    for file in &config_files {
+        merge_defaults(&mut defaults, &file.defaults);
+    }
+
+    let mut seen_providers: Vec<(ProviderSource, &Path, usize)> = vec![];
+    let mut seen_groups: Vec<(&String, &Path, usize)> = vec![];
+    let known_keys = provider_keys();
+    for file in &config_files {
+        let text = file.text.as_str();
+        let spans = file.key_spans(false);
+        for key in file.unknown.keys() {
+            report(
+                &file.path,
+                spans.get(key).map(|span| file.line_at(span.start)),
+                format!("Unknown key {}", key),
+            );
+        }
+
+        for repository_override in &file.overrides {
+            if let Err(e) = repository_override.get_ref().matcher() {
+                report(
+                    &file.path,
+                    Some(file.line_at(repository_override.span().start)),
+                    format!("{:#}", e),
+                );
+            }
+        }
+
+        for group in &file.groups {
+            let line = file.line_at(group.span().start);
+            if let Err(e) = group.get_ref().matcher() {
+                report(&file.path, Some(line), format!("{:#}", e));
+            }
+            let name = &group.get_ref().name;
+            if let Some((_, other_path, other_line)) =
+                seen_groups.iter().find(|(other, _, _)| *other == name)
+            {
+                report(
+                    &file.path,
+                    Some(line),
+                    format!(
+                        "Group {} is already defined in {}:{}",
+                        name,
+                        other_path.display(),
+                        other_line
+                    ),
+                );
+            }
+            seen_groups.push((name, &file.path, line));
+        }
+
+        for raw_provider in &file.providers {
+            let span = raw_provider.span();
+            let line = file.line_at(span.start);
+            let provider = match file.parse_provider(raw_provider, &defaults) {
+                Ok(provider) => provider,
+                Err(e) => {
+                    report(&file.path, Some(line), format!("{:#}", e));
+                    continue;
+                }
+            };
+            let kind = raw_provider
+                .get_ref()
+                .get("provider")
+                .and_then(toml::Value::as_str)
+                .unwrap_or_default();
+            let known = known_keys.get(kind).cloned().unwrap_or_default();
+            for key in unknown_keys(raw_provider.get_ref(), &known) {
+                report(
+                    &file.path,
+                    line_of_key(text, span.clone(), key),
+                    format!("Unknown key {} in {}", key, provider),
+                );
+            }
+            if let Err(e) = provider.provider().validate() {
+                report(&file.path, Some(line), format!("{:#}", e));
+            } else if !provider.correctly_configured() {
+                report(
+                    &file.path,
+                    Some(line),
+                    format!("{} is not correctly configured", provider),
+                );
+            }
+            if let Some((_, other_path, other_line)) = seen_providers
+                .iter()
+                .find(|(other, _, _)| other == &provider)
+            {
+                report(
+                    &file.path,
+                    Some(line),
+                    format!(
+                        "{} is a duplicate of the provider defined in {}:{}",
+                        provider,
+                        other_path.display(),
+                        other_line
+                    ),
+                );
+            }
+            seen_providers.push((provider, &file.path, line));
+        }
+    }
+
+    for file in &config_files {
+        let text = file.text.as_str();
+        let spans = file.key_spans(true);
+        for (key, value) in &file.defaults {
+            let span = spans.get(key).cloned();
+            let line = span.as_ref().map(|span| file.line_at(span.start));
+            let unknown = match value.as_table() {
+                Some(_) if !PROVIDER_KINDS.contains(&key.as_str()) => {
+                    vec![(
+                        line,
+                        format!("Unknown provider {} in [defaults.{}]", key, key),
+                    )]
+                }
+                Some(kind_defaults) => match known_keys.get(key) {
+                    Some(known) => kind_defaults
+                        .keys()
+                        .filter(|k| !known.contains(*k))
+                        .map(|k| {
+                            (
+                                span.clone().and_then(|span| line_of_key(text, span, k)),
+                                format!("Unknown key {} in [defaults.{}]", k, key),
+                            )
+                        })
+                        .collect(),
+                    None => vec![],
+                },
+                None if !known_keys.values().any(|known| known.contains(key)) => {
+                    vec![(line, format!("Unknown key {} in [defaults]", key))]
+                }
+                None => vec![],
+            };
+            for (line, message) in unknown {
+                report(&file.path, line, message);
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// The position of a provider table within a configuration file.
+pub struct ProviderLocation {
+    pub file: PathBuf,
+    pub index: usize,
+}
+
+fn read_document(path: &Path) -> anyhow::Result<(String, toml_edit::Document)> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Cannot read file {}", path.display()))?;
+    let document = text
+        .parse::<toml_edit::Document>()
+        .with_context(|| format!("Error parsing TOML in file {}", path.display()))?;
+    Ok((text, document))
+}
+
+/// Find every provider of the given kind (like "github") with the given name in `files`.
+pub fn find_providers(
+    files: &[PathBuf],
+    kind: &str,
+    name: &str,
+) -> anyhow::Result<Vec<ProviderLocation>> {
+    let mut locations = vec![];
+    for path in files {
+        let (_, document) = read_document(path)?;
+        let providers = match document
+            .get("provider")
+            .and_then(|p| p.as_array_of_tables())
+        {
+            Some(providers) => providers,
+            None => continue,
+        };
+        for (index, table) in providers.iter().enumerate() {
+            let matches = |key: &str, expected: &str| {
+                table
+                    .get(key)
+                    .and_then(|v| v.as_str())
+                    .is_some_and(|v| v.eq_ignore_ascii_case(expected))
+            };
+            if matches("provider", kind) && matches("name", name) {
+                locations.push(ProviderLocation {
+                    file: path.clone(),
+                    index,
+                });
+            }
+        }
+    }
+    Ok(locations)
+}
+
+/// Apply `edit` to the providers in the file at `location`, preserving the formatting and
+/// comments in the rest of the file. Returns the contents of the file before and after the
+/// edit, without writing it. The edited file must still be a valid configuration once the
+/// workspace `defaults` are applied.
+pub fn edit_provider<F>(
+    location: &ProviderLocation,
+    defaults: &toml::Table,
+    edit: F,
+) -> anyhow::Result<(String, String)>
+where
+    F: FnOnce(&mut toml_edit::ArrayOfTables, usize) -> anyhow::Result<()>,
+{
+    let (before, mut document) = read_document(&location.file)?;
+    let providers = document
+        .get_mut("provider")
+        .and_then(|p| p.as_array_of_tables_mut())
+        .with_context(|| format!("No providers found in {}", location.file.display()))?;
+    edit(providers, location.index)?;
+    let after = document.to_string();
+
+    let file = ConfigFile::parse(&location.file, after.clone())
+        .with_context(|| "The edited configuration file would be invalid")?;
+    let mut file_defaults = defaults.clone();
+    merge_defaults(&mut file_defaults, &file.defaults);
+    for raw_provider in &file.providers {
+        let provider = file.parse_provider(raw_provider, &file_defaults)?;
+        provider
+            .provider()
+            .validate()
+            .with_context(|| format!("The edited provider would be invalid: {}", provider))?;
+    }
+    Ok((before, after))
 }
 
 impl Config {
@@ -42,30 +881,111 @@ impl Config {
         Config { files }
     }
 
-    pub fn read(&self) -> anyhow::Result<Vec<ProviderSource>> {
-        let mut all_providers = vec![];
+    pub fn read(&self) -> anyhow::Result<ConfigContents> {
+        let files = self
+            .files
+            .iter()
+            .filter(|path| path.exists())
+            .map(|path| ConfigFile::read(path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-        for path in &self.files {
-            if !path.exists() {
-                continue;
+        // Defaults apply to every provider in the workspace, whichever file they are in.
+        let mut all_contents = ConfigContents::default();
+        for file in &files {
+            merge_defaults(&mut all_contents.defaults, &file.defaults);
+        }
+        for file in &files {
+            all_contents.on_collision = all_contents.on_collision.or(file.on_collision);
+            for repository_override in &file.overrides {
+                repository_override.get_ref().matcher().with_context(|| {
+                    format!(
+                        "Error in the override in {} on line {}",
+                        file.path.display(),
+                        file.line_at(repository_override.span().start)
+                    )
+                })?;
+                all_contents
+                    .overrides
+                    .push(repository_override.get_ref().clone());
+            }
+            for group in &file.groups {
+                let error_location = || {
+                    format!(
+                        "Error in the group in {} on line {}",
+                        file.path.display(),
+                        file.line_at(group.span().start)
+                    )
+                };
+                let group = group.get_ref();
+                group.matcher().with_context(error_location)?;
+                if all_contents.groups.iter().any(|g| g.name == group.name) {
+                    return Err(anyhow!("Group {} is defined more than once", group.name))
+                        .with_context(error_location);
+                }
+                all_contents.groups.push(group.clone());
+            }
+            for provider in &file.providers {
+                all_contents
+                    .providers
+                    .push(file.parse_provider(provider, &all_contents.defaults)?);
             }
-            let file_contents = fs::read_to_string(path)
-                .with_context(|| format!("Cannot read file {}", path.display()))?;
-            let contents: ConfigContents = toml::from_str(file_contents.as_str())
-                .with_context(|| format!("Error parsing TOML in file {}", path.display()))?;
-            all_providers.extend(contents.providers);
         }
-        Ok(all_providers)
+        Ok(all_contents)
     }
-    pub fn write(&self, providers: Vec<ProviderSource>, config_path: &Path) -> anyhow::Result<()> {
-        let toml = toml::to_string(&ConfigContents { providers })?;
-        fs::write(config_path, toml)
+
+    /// Add `providers` to the end of the configuration file at `config_path`, keeping the
+    /// formatting and comments in the rest of the file.
+    pub fn write(&self, providers: &[ProviderSource], config_path: &Path) -> anyhow::Result<()> {
+        let mut document = if config_path.exists() {
+            read_document(config_path)?.1
+        } else {
+            toml_edit::Document::new()
+        };
+        let tables = document
+            .entry("provider")
+            .or_insert(toml_edit::Item::ArrayOfTables(Default::default()))
+            .as_array_of_tables_mut()
+            .with_context(|| format!("provider is not a list in {}", config_path.display()))?;
+        for provider in providers {
+            tables.push(provider_table(provider)?);
+        }
+        fs::write(config_path, document.to_string())
             .with_context(|| format!("Error writing to file {}", config_path.display()))?;
         Ok(())
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, Ord, PartialEq, PartialOrd)]
+/// Serialize `provider` for a configuration file. Settings that have their default value are
+/// left out, so that any `[defaults]` still apply to them.
+fn provider_table(provider: &ProviderSource) -> anyhow::Result<toml_edit::Table> {
+    let full = toml::Table::try_from(provider)?;
+    // The settings that can't be left out, found by leaving each one out in turn
+    let required: toml::Table = full
+        .iter()
+        .filter(|(key, _)| {
+            let mut without = full.clone();
+            without.remove(*key);
+            ProviderSource::deserialize(toml::Value::Table(without)).is_err()
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let defaults = toml::Table::try_from(ProviderSource::deserialize(toml::Value::Table(
+        required.clone(),
+    ))?)?;
+
+    // Parse the serialized provider, rather than building the table from `full`, to keep the
+    // fields in the order they are declared in.
+    let mut table = toml::to_string(provider)?
+        .parse::<toml_edit::Document>()?
+        .as_table()
+        .clone();
+    table.retain(|key, _| required.contains_key(key) || defaults.get(key) != full.get(key));
+    Ok(table)
+}
+
+pub static PROVIDER_KINDS: &[&str] = &["github", "gitlab"];
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Eq, Ord, PartialEq, PartialOrd)]
 #[serde(tag = "provider")]
 #[serde(rename_all = "lowercase")]
 #[derive(StructOpt)]
@@ -89,6 +1009,22 @@ impl ProviderSource {
     pub fn fetch_repositories(&self) -> anyhow::Result<Vec<Repository>> {
         self.provider().fetch_repositories()
     }
+
+    /// How repositories from the provider are cloned.
+    pub fn clone_options(&self) -> &CloneOptions {
+        match self {
+            Self::Gitlab(config) => &config.clone_options,
+            Self::Github(config) => &config.clone_options,
+        }
+    }
+
+    /// Identifies the provider in groups, like `github/my-company`.
+    pub fn id(&self) -> String {
+        match self {
+            Self::Gitlab(config) => format!("gitlab/{}", config.name),
+            Self::Github(config) => format!("github/{}", config.name),
+        }
+    }
 }
 
 impl fmt::Display for ProviderSource {
@@ -96,3 +1032,309 @@ impl fmt::Display for ProviderSource {
         write!(f, "{}", self.provider())
     }
 }
+
+/// A JSON Schema describing a configuration file, for editors to validate and autocomplete.
+pub fn config_schema() -> RootSchema {
+    let mut schema = schemars::schema_for!(ConfigFile);
+    // Every setting apart from the kind and name of a provider can be given in `[defaults]`.
+    // Unknown keys are ignored when reading providers, but `config check` reports them, so
+    // editors should too.
+    if let Some(Schema::Object(providers)) = schema.definitions.get_mut("ProviderSource") {
+        for variant in providers.subschemas().one_of.iter_mut().flatten() {
+            if let Schema::Object(variant) = variant {
+                let object = variant.object();
+                object
+                    .required
+                    .retain(|key| key == "provider" || key == "name");
+                object.additional_properties = Some(Box::new(Schema::Bool(false)));
+            }
+        }
+    }
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_schema_is_up_to_date() {
+        let expected = serde_json::to_string_pretty(&config_schema()).unwrap() + "\n";
+        assert!(
+            include_str!("../workspace.schema.json") == expected,
+            "workspace.schema.json is out of date, regenerate it with \
+             `git workspace config schema > workspace.schema.json`"
+        );
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        env::set_var("GIT_WORKSPACE_TEST_SET", "value");
+        env::set_var("GIT_WORKSPACE_TEST_EMPTY", "");
+        env::remove_var("GIT_WORKSPACE_TEST_UNSET");
+
+        assert_eq!(
+            interpolate("a/${GIT_WORKSPACE_TEST_SET}/b").unwrap(),
+            "a/value/b"
+        );
+        assert_eq!(
+            interpolate("${GIT_WORKSPACE_TEST_SET:-default}").unwrap(),
+            "value"
+        );
+        // An empty value uses the default, like in a shell
+        assert_eq!(
+            interpolate("${GIT_WORKSPACE_TEST_EMPTY:-default}").unwrap(),
+            "default"
+        );
+        assert_eq!(interpolate("${GIT_WORKSPACE_TEST_EMPTY}").unwrap(), "");
+        assert_eq!(interpolate("${GIT_WORKSPACE_TEST_EMPTY:-}").unwrap(), "");
+        assert_eq!(interpolate("${GIT_WORKSPACE_TEST_UNSET:-}x").unwrap(), "x");
+    }
+
+    #[test]
+    fn interpolate_escapes_dollars() {
+        env::set_var("GIT_WORKSPACE_TEST_ESCAPED", "value");
+        assert_eq!(
+            interpolate("$${GIT_WORKSPACE_TEST_ESCAPED}").unwrap(),
+            "${GIT_WORKSPACE_TEST_ESCAPED}"
+        );
+        assert_eq!(interpolate("$$$$").unwrap(), "$$");
+        // A lone `$` is kept as it is
+        assert_eq!(interpolate("a$b$").unwrap(), "a$b$");
+    }
+
+    #[test]
+    fn interpolate_errors() {
+        env::remove_var("GIT_WORKSPACE_TEST_MISSING");
+        let error = |text| interpolate(text).unwrap_err().to_string();
+        assert_eq!(
+            error("${GIT_WORKSPACE_TEST_MISSING}"),
+            "Environment variable GIT_WORKSPACE_TEST_MISSING is not set"
+        );
+        assert_eq!(
+            error("a/${GIT_WORKSPACE_TEST_SET"),
+            "Missing closing brace in a/${GIT_WORKSPACE_TEST_SET"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_on_their_line() {
+        let dir = std::env::temp_dir().join(format!("git-workspace-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("workspace.toml");
+        fs::write(
+            &path,
+            r#"bogus = 1
+
+[[provider]]
+provider = "github"
+name = "octocat"
+path = "github"
+
+[defaults]
+path = "repos"
+pathh = "repos"
+
+[defaults.github]
+url = "https://github.com"
+pathh = "repos"
+
+[defaults.bitbucket]
+path = "repos"
+"#,
+        )
+        .unwrap();
+        let diagnostics = check_config_files(&[path]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut problems: Vec<(Option<usize>, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.message.as_str()))
+            .filter(|(_, message)| !message.ends_with("is not correctly configured"))
+            .collect();
+        problems.sort();
+        assert_eq!(
+            problems,
+            [
+                (Some(1), "Unknown key bogus"),
+                (Some(10), "Unknown key pathh in [defaults]"),
+                (Some(14), "Unknown key pathh in [defaults.github]"),
+                (
+                    Some(16),
+                    "Unknown provider bitbucket in [defaults.bitbucket]"
+                ),
+            ]
+        );
+    }
+
+    /// Create a workspace directory containing `files`, returning its canonical path.
+    fn workspace_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("git-workspace-{}-{}", name, std::process::id()));
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dunce::canonicalize(dir).unwrap()
+    }
+
+    fn relative(workspace: &Path, files: anyhow::Result<Vec<PathBuf>>) -> Vec<String> {
+        files
+            .unwrap()
+            .iter()
+            .map(|file| {
+                let file = dunce::canonicalize(file).unwrap();
+                let relative = file.strip_prefix(workspace).unwrap();
+                relative.display().to_string().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_are_read_once() {
+        let workspace = workspace_with(
+            "include-once",
+            &[
+                (
+                    "workspace.toml",
+                    r#"include = ["common.toml", "./common.toml"]"#,
+                ),
+                ("workspace-extra.toml", r#"include = ["common.toml"]"#),
+                ("common.toml", r#"include = ["shared/more.toml"]"#),
+                ("shared/more.toml", ""),
+            ],
+        );
+        let files = relative(&workspace, all_config_files(&workspace));
+        fs::remove_dir_all(&workspace).unwrap();
+
+        assert_eq!(
+            files,
+            [
+                "workspace-extra.toml",
+                "common.toml",
+                "shared/more.toml",
+                "workspace.toml"
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_can_be_globs() {
+        let workspace = workspace_with(
+            "include-glob",
+            &[
+                ("workspace.toml", r#"include = ["*.toml", "teams/*.toml"]"#),
+                ("workspace-lock.toml", "repo = []"),
+                ("extra.toml", ""),
+                ("teams/b.toml", ""),
+                ("teams/a.toml", ""),
+                ("teams/nested/c.toml", ""),
+                ("teams/notes.txt", ""),
+            ],
+        );
+        let files = relative(&workspace, all_config_files(&workspace));
+        fs::remove_dir_all(&workspace).unwrap();
+
+        assert_eq!(
+            files,
+            [
+                "workspace.toml",
+                "extra.toml",
+                "teams/a.toml",
+                "teams/b.toml"
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_includes_are_skipped() {
+        let workspace = workspace_with(
+            "include-missing",
+            &[(
+                "workspace.toml",
+                r#"include = ["not-cloned-yet/workspace.toml", "missing/*.toml"]"#,
+            )],
+        );
+        let files = relative(&workspace, all_config_files(&workspace));
+        fs::remove_dir_all(&workspace).unwrap();
+
+        assert_eq!(files, ["workspace.toml"]);
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let workspace = workspace_with(
+            "include-cycle",
+            &[
+                ("workspace.toml", r#"include = ["a.toml"]"#),
+                ("a.toml", r#"include = ["b.toml"]"#),
+                ("b.toml", r#"include = ["a.toml"]"#),
+            ],
+        );
+        let error = all_config_files(&workspace).unwrap_err();
+        fs::remove_dir_all(&workspace).unwrap();
+
+        let a = workspace.join("a.toml").display().to_string();
+        let b = workspace.join("b.toml").display().to_string();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Configuration files include each other: {} -> {} -> {}",
+                a, b, a
+            )
+        );
+    }
+
+    #[test]
+    fn provider_table_leaves_out_defaults() {
+        let provider = ProviderSource::from_iter_safe([
+            "add",
+            "github",
+            "octocat",
+            "--env-name",
+            "MY_TOKEN",
+            "--depth",
+            "1",
+        ])
+        .unwrap();
+        let table = provider_table(&provider).unwrap();
+
+        let keys: Vec<&str> = table.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["provider", "name", "path", "env_var", "depth"]);
+    }
+
+    #[test]
+    fn false_provider_flags_are_known_keys() {
+        let dir = std::env::temp_dir().join(format!("git-workspace-check-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("workspace.toml");
+        fs::write(
+            &path,
+            r#"
+            [[provider]]
+            provider = "github"
+            name = "octocat"
+            path = "github"
+            single_branch = false
+
+            [[provider]]
+            provider = "gitlab"
+            name = "my-group"
+            path = "gitlab"
+            include_shared = false
+            single_branch = false
+            "#,
+        )
+        .unwrap();
+        let diagnostics = check_config_files(&[path]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let problems: Vec<&str> = diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .filter(|message| !message.ends_with("is not correctly configured"))
+            .collect();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
@@ -12,30 +12,46 @@ extern crate structopt;
 extern crate ureq;
 extern crate walkdir;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use std::time::Duration;
 
 use atomic_counter::{AtomicCounter, RelaxedCounter};
 use indicatif::{MultiProgress, ParallelProgressIterator, ProgressBar, ProgressStyle};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
 use anyhow::{anyhow, Context};
 use console::style;
+use serde::Serialize;
 
-use crate::config::{all_config_files, Config, ProviderSource};
+use crate::config::{
+    all_config_files, check_config_files, config_schema, edit_provider, find_providers,
+    CollisionStrategy, Config, ConfigContents, PathMatcher, ProviderSource, PROVIDER_KINDS,
+};
 use crate::lockfile::Lockfile;
-use crate::repository::Repository;
+use crate::output::{print_records, OutputFormat, RunOutput};
+use crate::providers::{host_from_url, DEFAULT_GITHUB_URL, DEFAULT_GITLAB_URL};
+use crate::repository::{CommandError, Repository, RepositoryStatus};
+use crate::workspaces::UserConfig;
 
 mod config;
 mod lockfile;
+mod output;
 mod providers;
 mod repository;
 mod utils;
+mod workspaces;
 
 #[derive(StructOpt)]
 #[structopt(name = "git-workspace", author, about)]
@@ -46,7 +62,13 @@ struct Args {
         parse(from_os_str),
         env = "GIT_WORKSPACE"
     )]
-    workspace: PathBuf,
+    workspace: Option<PathBuf>,
+    /// Use a workspace from the user config by name. This takes priority over --workspace.
+    #[structopt(short = "n", long = "workspace-name")]
+    workspace_name: Option<String>,
+    /// Run the command in every workspace in the user config
+    #[structopt(long = "all-workspaces", global = true)]
+    all_workspaces: bool,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -55,28 +77,78 @@ struct Args {
 enum Command {
     /// Update the workspace, removing and adding any repositories as needed.
     Update {
-        #[structopt(short = "t", long = "threads", default_value = "8")]
-        threads: usize,
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// Copy objects from the mirrors in this clone cache when cloning new repositories
+        #[structopt(long = "cache-dir", env = "GIT_WORKSPACE_CACHE", parse(from_os_str))]
+        cache_dir: Option<PathBuf>,
     },
     /// Fetch new commits for all repositories in the workspace
     Fetch {
-        #[structopt(short = "t", long = "threads", default_value = "8")]
-        threads: usize,
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// Fetch with libgit2 rather than running `git fetch` in each repository. This needs
+        /// git-workspace to be built with the native-fetch feature.
+        #[structopt(long = "native")]
+        native: bool,
     },
     /// Fetch all repositories from configured providers and write the lockfile
+    ///
+    /// Any repositories that have been renamed or transferred since the lockfile was last
+    /// written are moved to their new path.
     Lock {},
     /// Pull new commits on the primary branch for all repositories in the workspace
     SwitchAndPull {
-        #[structopt(short = "t", long = "threads", default_value = "8")]
-        threads: usize,
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+    },
+    /// Fetch the full history of repositories that were cloned shallow, partial or with a single
+    /// branch
+    Deepen {
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// Fetch this many more commits of shallow clones, rather than their full history
+        #[structopt(long = "depth")]
+        depth: Option<u32>,
     },
     /// List all repositories in the workspace
     ///
     /// This command will output the names of all known repositories in the workspace.
-    /// Passing --full will output absolute paths.
+    /// Passing --full will output absolute paths. Other formats include every repository in
+    /// the lockfile, whether it has been cloned or not.
     List {
         #[structopt(long = "full")]
         full: bool,
+        /// Print the results as text, json, ndjson or tsv
+        #[structopt(long = "format", default_value = "text", possible_values = OutputFormat::VARIANTS)]
+        format: OutputFormat,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+    },
+    /// Show which repositories have uncommitted changes, unpushed commits or stashes
+    ///
+    /// For each repository this shows the checked out branch (and the primary branch, if it is
+    /// different), the number of changed and untracked files, how far ahead and behind the
+    /// tracking branch it is and the number of stashes.
+    Status {
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// Only show repositories with changes, untracked files, unpushed commits or stashes
+        #[structopt(long = "only-dirty")]
+        only_dirty: bool,
+        /// Print the results as text, json, ndjson or tsv
+        #[structopt(long = "format", default_value = "text", possible_values = OutputFormat::VARIANTS)]
+        format: OutputFormat,
     },
     /// Archive repositories that don't exist in the workspace anymore.
     Archive {
@@ -89,12 +161,24 @@ enum Command {
     /// This command executes the "command" in all git workspace repositories.
     /// The program will receive the given "args", and have it's working directory
     /// set to the repository directory.
+    ///
+    /// The placeholders {path}, {name}, {url}, {branch} and {provider} in the command and
+    /// args are replaced with the repository's absolute path, path in the workspace, URL,
+    /// branch and provider. The same values are available in the GW_REPO_PATH, GW_REPO_NAME,
+    /// GW_REPO_URL, GW_REPO_BRANCH and GW_PROVIDER environment variables.
+    #[structopt(setting = AppSettings::TrailingVarArg)]
     Run {
-        #[structopt(short = "t", long = "threads", default_value = "8")]
-        threads: usize,
-        #[structopt(required = true)]
-        command: String,
-        args: Vec<String>,
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// Print the results as text, json, ndjson or tsv
+        #[structopt(long = "format", default_value = "text", possible_values = OutputFormat::VARIANTS)]
+        format: OutputFormat,
+        #[structopt(flatten)]
+        output: RunOutputOptions,
+        #[structopt(flatten)]
+        command: RunCommand,
     },
     /// Add a provider to the configuration
     Add {
@@ -103,27 +187,417 @@ enum Command {
         #[structopt(subcommand)]
         command: ProviderSource,
     },
+    /// Interactively add a provider to the configuration
+    ///
+    /// This asks for the kind of provider, the URL of the instance and the user, organisation or
+    /// group to clone, then checks that your access token works and shows how many repositories
+    /// would be cloned before writing the configuration.
+    Init {
+        #[structopt(long = "file", default_value = "workspace.toml")]
+        file: PathBuf,
+    },
+    /// Remove a provider from the configuration
+    Remove {
+        #[structopt(flatten)]
+        target: ProviderTarget,
+    },
+    /// Change the settings of a provider in the configuration
+    ///
+    /// Values given with --set are parsed as TOML, so lists can be given like
+    /// --set 'exclude=["foo.*", "bar"]'. Anything that is not valid TOML is treated as a string.
+    Edit {
+        #[structopt(flatten)]
+        target: ProviderTarget,
+        /// Set a key on the provider, like --set path=github-work
+        #[structopt(long = "set", parse(try_from_str = parse_key_value))]
+        set: Vec<(String, String)>,
+        /// Remove a key from the provider, reverting it to the default value
+        #[structopt(long = "unset")]
+        unset: Vec<String>,
+    },
+    /// Manage a cache of mirrors that speeds up cloning repositories
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+    /// Manage sparse checkouts of repositories
+    Sparse {
+        #[structopt(subcommand)]
+        command: SparseCommand,
+    },
+    /// Inspect the workspace configuration files
+    Config {
+        #[structopt(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
-fn main() -> anyhow::Result<()> {
+impl Command {
+    /// Whether the output of the command is meant for other programs, so nothing else should
+    /// be printed alongside it.
+    fn is_machine_readable(&self) -> bool {
+        match self {
+            // Listing repositories is often piped into other commands, even as text.
+            Command::List { .. } => true,
+            _ => self
+                .format()
+                .is_some_and(|format| format != OutputFormat::Text),
+        }
+    }
+
+    /// The format the command prints its results in, if it has a --format option.
+    fn format(&self) -> Option<OutputFormat> {
+        match self {
+            Command::List { format, .. }
+            | Command::Status { format, .. }
+            | Command::Run { format, .. } => Some(*format),
+            _ => None,
+        }
+    }
+}
+
+/// Options for commands that run in many repositories at once.
+#[derive(StructOpt)]
+struct ParallelOptions {
+    #[structopt(short = "t", long = "threads", default_value = "8")]
+    threads: usize,
+    /// Don't start any more repositories after the first failure
+    #[structopt(long = "fail-fast")]
+    fail_fast: bool,
+    /// Don't start any more repositories after this many failures, which must be at least 1
+    #[structopt(long = "max-failures", conflicts_with = "fail-fast")]
+    max_failures: Option<NonZeroUsize>,
+    /// Kill the command in a repository, and any processes it started, after this many seconds
+    #[structopt(long = "timeout")]
+    timeout: Option<u64>,
+    /// Retry a repository this many times when git fails with what looks like a network error,
+    /// waiting twice as long before each attempt
+    #[structopt(long = "retries", default_value = "0")]
+    retries: u32,
+}
+
+impl ParallelOptions {
+    /// The number of failures after which no more repositories are started, if any.
+    fn max_failures(&self) -> Option<usize> {
+        if self.fail_fast {
+            Some(1)
+        } else {
+            self.max_failures.map(NonZeroUsize::get)
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+/// The command `run` executes in each repository.
+#[derive(StructOpt)]
+struct RunCommand {
+    /// Run the command and args, joined by spaces, as a shell snippet with `sh -c`. This
+    /// allows pipes, `&&` and environment variables. Placeholders become quoted references to
+    /// their environment variables, like "$GW_REPO_BRANCH".
+    #[structopt(long = "shell")]
+    shell: bool,
+    /// The command and its arguments. Everything after the start of the command is passed on
+    /// as it is, even if it starts with a dash, like `git log -1`
+    #[structopt(required = true)]
+    command: Vec<String>,
+}
+
+impl RunCommand {
+    /// The program and arguments to execute in `repository`.
+    fn for_repository(&self, workspace: &Path, repository: &Repository) -> (String, Vec<String>) {
+        let words = self
+            .command
+            .iter()
+            .map(|word| repository.substitute_placeholders(workspace, word, self.shell));
+        if self.shell {
+            let snippet = words.collect::<Vec<_>>().join(" ");
+            ("sh".to_string(), vec!["-c".to_string(), snippet])
+        } else {
+            let mut words = words;
+            let program = words.next().unwrap_or_default();
+            (program, words.collect())
+        }
+    }
+}
+
+impl fmt::Display for RunCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.command.join(" "))
+    }
+}
+
+/// How `run` shows the output of the command.
+#[derive(StructOpt)]
+struct RunOutputOptions {
+    /// How to show the output of the command. "progress" shows the last line from each
+    /// repository, "grouped" prints all the output of each repository once it finishes and
+    /// "prefixed" prints every line as it arrives, prefixed with the repository.
+    #[structopt(long = "output", default_value = "progress", possible_values = RunOutput::VARIANTS)]
+    mode: RunOutput,
+    /// Also write the output of each repository to a log file in this directory
+    #[structopt(long = "log-dir", parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+}
+
+/// Identifies a single provider across all configuration files.
+#[derive(StructOpt)]
+struct ProviderTarget {
+    /// The kind of provider, like "github" or "gitlab"
+    provider: String,
+    /// The name of the user, organisation or group
+    name: String,
+    /// Only look for the provider in this configuration file
+    #[structopt(long = "file", parse(from_os_str))]
+    file: Option<PathBuf>,
+    /// Show the changes that would be made without writing them
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+}
+
+/// Limits a command to some of the repositories in the workspace. If both are given, a
+/// repository must be in one of the groups and match one of the filters.
+#[derive(StructOpt)]
+struct RepositoryFilter {
+    /// Only include repositories in this group from the configuration. Can be given more than once.
+    #[structopt(short = "g", long = "group")]
+    groups: Vec<String>,
+    /// Only include repositories whose path matches this glob. Can be given more than once.
+    #[structopt(long = "filter")]
+    filters: Vec<String>,
+}
+
+impl RepositoryFilter {
+    /// Keep only the repositories that are selected by this filter.
+    fn apply(
+        &self,
+        workspace: &Path,
+        repositories: Vec<Repository>,
+    ) -> anyhow::Result<Vec<Repository>> {
+        if self.groups.is_empty() && self.filters.is_empty() {
+            return Ok(repositories);
+        }
+        let contents = if self.groups.is_empty() {
+            ConfigContents::default()
+        } else {
+            Config::new(all_config_files(workspace)?)
+                .read()
+                .with_context(|| "Error reading config files")?
+        };
+        let groups = self
+            .groups
+            .iter()
+            .map(|name| contents.group(name)?.matcher())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let filters = self
+            .filters
+            .iter()
+            .map(|glob| PathMatcher::new(&None, &Some(glob.clone())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(repositories
+            .into_iter()
+            .filter(|r| groups.is_empty() || groups.iter().any(|g| g.is_match(r)))
+            .filter(|r| filters.is_empty() || filters.iter().any(|f| f.is_match(r)))
+            .collect())
+    }
+}
+
+/// Read the repositories in the lockfile that are selected by `filter`.
+fn read_repositories(
+    workspace: &Path,
+    filter: &RepositoryFilter,
+) -> anyhow::Result<Vec<Repository>> {
+    let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
+    let repositories = lockfile.read().with_context(|| "Error reading lockfile")?;
+    filter.apply(workspace, repositories)
+}
+
+fn parse_key_value(value: &str) -> anyhow::Result<(String, String)> {
+    match value.split_once('=') {
+        Some((key, value)) => Ok((key.trim().to_string(), value.to_string())),
+        None => Err(anyhow!("Expected KEY=VALUE, got {}", value)),
+    }
+}
+
+#[derive(StructOpt)]
+enum CacheCommand {
+    /// Create or update a bare mirror of each repository in the cache
+    ///
+    /// Mirrors are keyed by URL, so a cache can be shared between workspaces and people.
+    /// `git workspace update --cache-dir` then copies objects from them when cloning, and only
+    /// downloads what the mirror is missing.
+    Update {
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+        /// The cache directory
+        #[structopt(long = "cache-dir", env = "GIT_WORKSPACE_CACHE", parse(from_os_str))]
+        cache_dir: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+enum SparseCommand {
+    /// Make the sparse checkout of each repository match the lockfile
+    ///
+    /// Run this after changing the sparse directories of an override and running
+    /// `git workspace lock`. Repositories that no longer have any sparse directories are
+    /// changed back to a full checkout.
+    Sync {
+        #[structopt(flatten)]
+        parallel: ParallelOptions,
+        #[structopt(flatten)]
+        filter: RepositoryFilter,
+    },
+}
+
+#[derive(StructOpt)]
+enum ConfigCommand {
+    /// Check all configuration files for errors
+    ///
+    /// This reports unknown keys, invalid regular expressions and path templates, providers that
+    /// are not correctly configured and providers that are defined more than once.
+    Check {},
+    /// Print a JSON Schema for configuration files
+    ///
+    /// Editors can use this to validate and autocomplete workspace.toml.
+    Schema {},
+}
+
+fn main() {
     // Parse our arguments to Args using structopt.
     let args = Args::from_args();
-    handle_main(args)
+    if let Err(e) = handle_main(args) {
+        eprintln!("Error: {:?}", e);
+        let exit_code = match e.downcast_ref::<RepositoriesFailed>() {
+            Some(_) => RepositoriesFailed::EXIT_CODE,
+            None => 1,
+        };
+        process::exit(exit_code);
+    }
 }
 
 /// Our actual main function.
 fn handle_main(args: Args) -> anyhow::Result<()> {
+    let workspaces = selected_workspaces(&args)?;
+    if workspaces.len() > 1 {
+        if let Command::Add { .. }
+        | Command::Init { .. }
+        | Command::Remove { .. }
+        | Command::Edit { .. } = args.command
+        {
+            return Err(anyhow!(
+                "--all-workspaces cannot be used to change the configuration"
+            ));
+        }
+        // Each workspace prints its own JSON array or TSV header, which together aren't valid
+        // JSON or TSV. NDJSON can be concatenated.
+        if let Some(OutputFormat::Json | OutputFormat::Tsv) = args.command.format() {
+            return Err(anyhow!(
+                "--all-workspaces can only be used with --format text or ndjson"
+            ));
+        }
+    }
+    // With more than one workspace, a failure in one doesn't stop the others from running.
+    let mut failed_workspaces: Vec<(&str, anyhow::Error)> = vec![];
+    for (name, path) in &workspaces {
+        let result = resolve_workspace_path(path).and_then(|workspace_path| {
+            match name {
+                // Listing repositories is often piped into other commands, so we don't add
+                // headers.
+                Some(name) if !args.command.is_machine_readable() => println!(
+                    "{} {}",
+                    style(name).bold(),
+                    style(workspace_path.display()).dim()
+                ),
+                _ => {}
+            }
+            run_command(&workspace_path, &args.command)
+        });
+        match name {
+            Some(name) if workspaces.len() > 1 => {
+                if let Err(e) = result {
+                    eprintln!("{} {}: {:?}", style("Error in workspace").red(), name, e);
+                    failed_workspaces.push((name, e));
+                }
+            }
+            Some(name) => result.with_context(|| format!("Error in workspace {}", name))?,
+            None => result?,
+        }
+    }
+    workspaces_failed(failed_workspaces)
+}
+
+/// Combine the errors from each workspace that failed into one. If the only errors are
+/// repositories failing, this is a `RepositoriesFailed` with the total of each workspace.
+fn workspaces_failed(failed_workspaces: Vec<(&str, anyhow::Error)>) -> anyhow::Result<()> {
+    if failed_workspaces.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = failed_workspaces.iter().map(|(name, _)| *name).collect();
+    let message = format!("Errors in workspaces {}", names.join(", "));
+    let repositories_failed = failed_workspaces
+        .iter()
+        .map(|(_, e)| e.downcast_ref::<RepositoriesFailed>())
+        .collect::<Option<Vec<_>>>();
+    match repositories_failed {
+        Some(repositories_failed) => Err(anyhow::Error::new(RepositoriesFailed {
+            failed: repositories_failed.iter().map(|r| r.failed).sum(),
+            timed_out: repositories_failed.iter().map(|r| r.timed_out).sum(),
+            skipped: repositories_failed.iter().map(|r| r.skipped).sum(),
+        })
+        .context(message)),
+        None => Err(anyhow!(message)),
+    }
+}
+
+/// The workspaces to run the command in, with their names if they came from the user config.
+/// With --all-workspaces this is every workspace in the user config. Otherwise it is the
+/// workspace given by --workspace-name, --workspace or `GIT_WORKSPACE`, or the default
+/// workspace in the user config.
+fn selected_workspaces(args: &Args) -> anyhow::Result<Vec<(Option<String>, PathBuf)>> {
+    let user_config = UserConfig::read()?;
+    if args.all_workspaces {
+        if user_config.workspaces.is_empty() {
+            return Err(anyhow!(
+                "No workspaces are configured in {}",
+                UserConfig::path().unwrap_or_default().display()
+            ));
+        }
+        return Ok(user_config
+            .workspaces
+            .into_iter()
+            .map(|(name, path)| (Some(name), path))
+            .collect());
+    }
+    let name = match (&args.workspace_name, &args.workspace) {
+        (Some(name), _) => name,
+        (None, Some(path)) => return Ok(vec![(None, path.clone())]),
+        (None, None) => user_config.default.as_ref().with_context(|| {
+            "No workspace given. Pass --workspace or --workspace-name, set GIT_WORKSPACE or set \
+             a default workspace in the user config"
+        })?,
+    };
+    let path = user_config.workspace(name)?.clone();
+    Ok(vec![(Some(name.clone()), path)])
+}
+
+/// Expand and canonicalize the path to a workspace, creating it if it does not exist.
+fn resolve_workspace_path(workspace: &Path) -> anyhow::Result<PathBuf> {
     // Convert our workspace path to a PathBuf. We cannot use the value given directly as
     // it could contain a tilde, so we run `expanduser` on it _if_ we are on a Unix platform.
     // On Windows this isn't supported.
     let expanded_workspace_path;
     #[cfg(not(unix))]
     {
-        expanded_workspace_path = PathBuf::from(args.workspace);
+        expanded_workspace_path = workspace.to_path_buf();
     }
     #[cfg(unix)]
     {
-        expanded_workspace_path = expanduser::expanduser(args.workspace.to_string_lossy())
+        expanded_workspace_path = expanduser::expanduser(workspace.to_string_lossy())
             .with_context(|| "Error expanding git workspace path")?;
     }
 
@@ -152,29 +626,56 @@ fn handle_main(args: Args) -> anyhow::Result<()> {
             &expanded_workspace_path.display()
         )
     })?;
+    Ok(workspace_path)
+}
 
+/// Run a sub command in a single workspace.
+fn run_command(workspace_path: &Path, command: &Command) -> anyhow::Result<()> {
     // Run our sub command. Pretty self-explanatory.
-    match args.command {
-        Command::List { full } => list(&workspace_path, full)?,
-        Command::Update { threads } => {
-            lock(&workspace_path)?;
-            update(&workspace_path, threads)?
+    match command {
+        Command::List {
+            full,
+            format,
+            filter,
+        } => list(workspace_path, *full, *format, filter)?,
+        Command::Update {
+            parallel,
+            filter,
+            cache_dir,
+        } => {
+            let cache_dir = cache_dir.as_deref();
+            let config_files = all_config_files(workspace_path)?;
+            lock(workspace_path)?;
+            update(workspace_path, parallel, filter, cache_dir)?;
+            // Configuration files can be included from repositories inside the workspace, which
+            // may have only just been cloned.
+            if all_config_files(workspace_path)? != config_files {
+                println!("Configuration files have changed, updating again");
+                lock(workspace_path)?;
+                update(workspace_path, parallel, filter, cache_dir)?;
+            }
         }
+        Command::Status {
+            parallel,
+            filter,
+            only_dirty,
+            format,
+        } => status(workspace_path, parallel, filter, *only_dirty, *format)?,
         Command::Lock {} => {
-            lock(&workspace_path)?;
+            lock(workspace_path)?;
         }
         Command::Archive { force } => {
             // Archive any repositories that have been deleted from the lockfile.
-            lock(&workspace_path)?;
+            lock(workspace_path)?;
 
             let lockfile = Lockfile::new(workspace_path.join("workspace-lock.toml"));
             let repositories = lockfile.read().context("Error reading lockfile")?;
-            let repos_to_archive = get_all_repositories_to_archive(&workspace_path, repositories)?;
+            let repos_to_archive = get_all_repositories_to_archive(workspace_path, repositories)?;
 
             if !force {
                 for (from_path, to_path) in &repos_to_archive {
-                    let relative_from_path = from_path.strip_prefix(&workspace_path).unwrap();
-                    let relative_to_path = to_path.strip_prefix(&workspace_path).unwrap();
+                    let relative_from_path = from_path.strip_prefix(workspace_path).unwrap();
+                    let relative_to_path = to_path.strip_prefix(workspace_path).unwrap();
                     println!(
                         "Move {} to {}",
                         style(relative_from_path.display()).yellow(),
@@ -185,7 +686,7 @@ fn handle_main(args: Args) -> anyhow::Result<()> {
                     "Will archive {} projects",
                     style(repos_to_archive.len()).red()
                 );
-                if repos_to_archive.is_empty() || !utils::confirm("Proceed?", false, " ", true) {
+                if repos_to_archive.is_empty() || !utils::confirm("Proceed?", false, " ", true)? {
                     return Ok(());
                 }
             }
@@ -193,14 +694,79 @@ fn handle_main(args: Args) -> anyhow::Result<()> {
                 archive_repositories(repos_to_archive)?;
             }
         }
-        Command::Fetch { threads } => fetch(&workspace_path, threads)?,
-        Command::Add { file, command } => add_provider_to_config(&workspace_path, command, &file)?,
+        Command::Fetch {
+            parallel,
+            filter,
+            native,
+        } => fetch(workspace_path, parallel, filter, *native)?,
+        Command::Add { file, command } => add_provider_to_config(workspace_path, command, file)?,
+        Command::Init { file } => init(workspace_path, file)?,
         Command::Run {
-            threads,
+            parallel,
+            filter,
+            format,
+            output,
             command,
-            args,
-        } => execute_cmd(&workspace_path, threads, command, args)?,
-        Command::SwitchAndPull { threads } => pull_all_repositories(&workspace_path, threads)?,
+        } => execute_cmd(workspace_path, parallel, filter, *format, output, command)?,
+        Command::SwitchAndPull { parallel, filter } => {
+            pull_all_repositories(workspace_path, parallel, filter)?
+        }
+        Command::Deepen {
+            parallel,
+            filter,
+            depth,
+        } => deepen(workspace_path, parallel, filter, *depth)?,
+        Command::Remove { target } => {
+            modify_provider(workspace_path, target, |providers, index| {
+                providers.remove(index);
+                Ok(())
+            })?
+        }
+        Command::Edit { target, set, unset } => {
+            modify_provider(workspace_path, target, |providers, index| {
+                let table = providers
+                    .get_mut(index)
+                    .with_context(|| "Provider not found")?;
+                for (key, value) in set {
+                    // Anything that isn't a valid TOML value is treated as a plain string
+                    let mut value = value
+                        .parse::<toml_edit::Value>()
+                        .unwrap_or_else(|_| toml_edit::Value::from(value.as_str()));
+                    // Keep any comments and whitespace around an existing value
+                    match table.get_mut(key).and_then(|item| item.as_value_mut()) {
+                        Some(existing) => {
+                            *value.decor_mut() = existing.decor().clone();
+                            *existing = value;
+                        }
+                        None => table[key.as_str()] = toml_edit::value(value),
+                    }
+                }
+                for key in unset {
+                    if table.remove(key).is_none() {
+                        return Err(anyhow!("Provider does not have a {} key", key));
+                    }
+                }
+                Ok(())
+            })?
+        }
+        Command::Cache { command } => match command {
+            CacheCommand::Update {
+                parallel,
+                filter,
+                cache_dir,
+            } => update_cache(workspace_path, parallel, filter, cache_dir)?,
+        },
+        Command::Sparse { command } => match command {
+            SparseCommand::Sync { parallel, filter } => {
+                sync_sparse_checkouts(workspace_path, parallel, filter)?
+            }
+        },
+        Command::Config { command } => match command {
+            ConfigCommand::Check {} => check_config(workspace_path)?,
+            ConfigCommand::Schema {} => {
+                println!("{}", serde_json::to_string_pretty(&config_schema())?)
+            }
+        },
     };
     Ok(())
 }
@@ -208,18 +774,25 @@ fn handle_main(args: Args) -> anyhow::Result<()> {
 /// Add a given ProviderSource to our configuration file.
 fn add_provider_to_config(
     workspace: &Path,
-    provider_source: ProviderSource,
+    provider_source: &ProviderSource,
     file: &Path,
 ) -> anyhow::Result<()> {
     if !provider_source.correctly_configured() {
         return Err(anyhow!("Provider is not correctly configured"));
     }
     let path_to_config = workspace.join(file);
-    // Load and parse our configuration files
-    let config = Config::new(vec![path_to_config]);
-    let mut sources = config.read().with_context(|| "Error reading config file")?;
+    // Load and parse our configuration files. We read all of them, as defaults can be set in
+    // any file.
+    let mut config_files = all_config_files(workspace).context("Error loading config files")?;
+    if !config_files.contains(&path_to_config) {
+        config_files.push(path_to_config);
+    }
+    let config = Config::new(config_files);
+    let contents = config
+        .read()
+        .with_context(|| "Error reading config files")?;
     // Ensure we don't add duplicates:
-    if sources.iter().any(|s| s == &provider_source) {
+    if contents.providers.iter().any(|s| s == provider_source) {
         println!("Entry already exists, skipping");
     } else {
         println!(
@@ -228,32 +801,193 @@ fn add_provider_to_config(
             style(&workspace.join(file).display()).green()
         );
         // Push the provider into the source and write it to the configuration file
-        sources.push(provider_source);
         config
-            .write(sources, &workspace.join(file))
+            .write(std::slice::from_ref(provider_source), &workspace.join(file))
             .with_context(|| "Error writing config file")?;
     }
     Ok(())
 }
 
+/// Ask for the details of a provider, check that it works and add it to our configuration file.
+fn init(workspace: &Path, file: &Path) -> anyhow::Result<()> {
+    println!(
+        "Adding a provider to {}",
+        style(workspace.join(file).display()).green()
+    );
+    let kind = loop {
+        let kind =
+            utils::prompt("Provider (github or gitlab)", Some("github"), ": ")?.to_lowercase();
+        if PROVIDER_KINDS.contains(&kind.as_str()) {
+            break kind;
+        }
+        println!("Error: unknown provider {}", kind);
+    };
+    let (default_url, default_env_var) = match kind.as_str() {
+        "github" => {
+            println!("For Github Enterprise, the URL should be http(s)://HOSTNAME/api/graphql");
+            (DEFAULT_GITHUB_URL, "GITHUB_TOKEN")
+        }
+        _ => (DEFAULT_GITLAB_URL, "GITLAB_TOKEN"),
+    };
+    let url = utils::prompt("Instance URL", Some(default_url), ": ")?;
+    let name = utils::prompt("User, organisation or group name", None, ": ")?;
+    let env_var = utils::prompt(
+        "Environment variable containing your access token",
+        Some(default_env_var),
+        ": ",
+    )?;
+    let path = utils::prompt("Directory to clone repositories into", Some(&kind), ": ")?;
+
+    let mut table = toml::Table::new();
+    for (key, value) in [
+        ("provider", kind),
+        ("name", name),
+        ("url", url),
+        ("env_var", env_var),
+        ("path", path.clone()),
+    ] {
+        table.insert(key.to_string(), toml::Value::String(value));
+    }
+    let provider: ProviderSource = toml::Value::Table(table).try_into()?;
+    // This explains how to create a token if it is missing.
+    if !provider.correctly_configured() {
+        return Err(anyhow!(
+            "Provider is not correctly configured, run `git workspace init` again once it is"
+        ));
+    }
+
+    println!("Checking your access token...");
+    let user = provider
+        .provider()
+        .verify_token()
+        .with_context(|| "Your access token did not work")?;
+    println!("Authenticated as {}", style(user).green());
+
+    println!("Fetching repositories...");
+    let repositories = provider
+        .fetch_repositories()
+        .with_context(|| format!("Error fetching repositories from {}", provider))?;
+    println!(
+        "{} repositories would be cloned into {}",
+        style(repositories.len()).green(),
+        style(&path).green()
+    );
+    for repo in repositories.iter().take(5) {
+        println!("  {}", repo.name());
+    }
+    if repositories.len() > 5 {
+        println!("  ...");
+    }
+
+    if !utils::confirm("Add this provider?", true, " ", true)? {
+        return Ok(());
+    }
+    add_provider_to_config(workspace, &provider, file)?;
+    println!(
+        "Run {} to clone the repositories",
+        style("`git workspace update`").yellow()
+    );
+    Ok(())
+}
+
+/// Find a single provider in our configuration files and apply `edit` to it, preserving the
+/// formatting of the file. With `--dry-run` the changes are printed rather than written.
+fn modify_provider<F>(workspace: &Path, target: &ProviderTarget, edit: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut toml_edit::ArrayOfTables, usize) -> anyhow::Result<()>,
+{
+    let config_files = match &target.file {
+        Some(file) => vec![workspace.join(file)],
+        None => all_config_files(workspace).context("Error loading config files")?,
+    };
+    let mut locations = find_providers(&config_files, &target.provider, &target.name)?;
+    let location = match locations.len() {
+        0 => {
+            return Err(anyhow!(
+                "No {} provider named {} was found",
+                target.provider,
+                target.name
+            ))
+        }
+        1 => locations.remove(0),
+        _ => {
+            for location in &locations {
+                eprintln!(
+                    "Found in {} (provider #{})",
+                    style(location.file.display()).yellow(),
+                    location.index + 1
+                );
+            }
+            return Err(anyhow!(
+                "More than one {} provider named {} was found. Use --file to choose one.",
+                target.provider,
+                target.name
+            ));
+        }
+    };
+
+    // Defaults from every file are needed to check the edited provider is still valid
+    let all_files = all_config_files(workspace).context("Error loading config files")?;
+    let defaults = Config::new(all_files).read()?.defaults;
+    let (before, after) = edit_provider(&location, &defaults, edit)?;
+    if before == after {
+        println!("No changes to make");
+    } else if target.dry_run {
+        utils::print_diff(&location.file, &before, &after);
+    } else {
+        std::fs::write(&location.file, after)
+            .with_context(|| format!("Error writing to file {}", location.file.display()))?;
+        println!("Updated {}", style(location.file.display()).green());
+    }
+    Ok(())
+}
+
+/// Check all our configuration files, printing any problems that are found.
+fn check_config(workspace: &Path) -> anyhow::Result<()> {
+    let config_files = all_config_files(workspace).context("Error loading config files")?;
+    if config_files.is_empty() {
+        anyhow::bail!("No configuration files found: Are you in the right workspace?")
+    }
+    let diagnostics = check_config_files(&config_files);
+    if diagnostics.is_empty() {
+        println!(
+            "Checked {} configuration files, no problems found",
+            style(config_files.len()).green()
+        );
+        return Ok(());
+    }
+    for diagnostic in &diagnostics {
+        eprintln!("{} {}", style("error:").red(), diagnostic);
+    }
+    Err(anyhow!("Found {} problems", diagnostics.len()))
+}
+
 /// Update our workspace. This clones any new repositories and archives old ones.
-fn update(workspace: &Path, threads: usize) -> anyhow::Result<()> {
+fn update(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    cache_dir: Option<&Path>,
+) -> anyhow::Result<()> {
     // Load our lockfile
     let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
     let repositories = lockfile.read().with_context(|| "Error reading lockfile")?;
+    let repositories_to_update = filter.apply(workspace, repositories.clone())?;
 
-    println!("Updating {} repositories", repositories.len());
+    println!("Updating {} repositories", repositories_to_update.len());
 
-    map_repositories(&repositories, threads, |r, progress_bar| {
+    let failures = map_repositories(&repositories_to_update, parallel, |r, progress_bar| {
         // Only clone repositories that don't exist
         if !r.exists(workspace) {
-            r.clone(workspace, progress_bar)?;
+            r.clone(workspace, progress_bar, parallel.timeout(), cache_dir)?;
             // Maybe this should always be run, but whatever. It's fine for now.
             r.set_upstream(workspace)?;
         }
         Ok(())
     })?;
 
+    // Repositories that are filtered out are still part of the workspace, so they are not
+    // archived.
     let repos_to_archive = get_all_repositories_to_archive(workspace, repositories)?;
     if !repos_to_archive.is_empty() {
         println!(
@@ -266,19 +1000,22 @@ fn update(workspace: &Path, threads: usize) -> anyhow::Result<()> {
         );
     }
 
-    Ok(())
+    failures.check()
 }
 
-fn pull_all_repositories(workspace: &Path, threads: usize) -> anyhow::Result<()> {
-    let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
-    let repositories = lockfile.read().with_context(|| "Error reading lockfile")?;
+fn pull_all_repositories(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+) -> anyhow::Result<()> {
+    let repositories = read_repositories(workspace, filter)?;
 
     println!(
         "Switching to the primary branch and pulling {} repositories",
         repositories.len()
     );
 
-    map_repositories(&repositories, threads, |r, progress_bar| {
+    map_repositories(&repositories, parallel, |r, progress_bar| {
         r.switch_to_primary_branch(workspace)?;
         let pull_args = match (&r.upstream, &r.branch) {
             // This fucking sucks, but it's because my abstractions suck ass.
@@ -290,24 +1027,274 @@ fn pull_all_repositories(workspace: &Path, threads: usize) -> anyhow::Result<()>
             ],
             _ => vec!["pull".to_string()],
         };
-        r.execute_cmd(workspace, progress_bar, "git", &pull_args)?;
+        r.execute_cmd(
+            workspace,
+            progress_bar,
+            parallel.timeout(),
+            "git",
+            &pull_args,
+        )?;
         Ok(())
-    })?;
+    })?
+    .check()
+}
 
-    Ok(())
+/// Fetch what shallow, partial and single branch clones left out
+fn deepen(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    depth: Option<u32>,
+) -> anyhow::Result<()> {
+    // We only care about repositories that exist
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| r.exists(workspace))
+        .collect();
+
+    println!("Deepening {} repositories", repositories.len());
+
+    map_repositories(&repositories, parallel, |r, progress_bar| {
+        r.deepen(workspace, progress_bar, parallel.timeout(), depth)
+    })?
+    .check()
+}
+
+/// Create or update the mirror of every repository in a clone cache
+fn update_cache(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    cache_dir: &Path,
+) -> anyhow::Result<()> {
+    // Repositories with the same URL share a mirror, so only update it once
+    let mut seen = HashSet::new();
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| seen.insert(r.cache_path(cache_dir)))
+        .collect();
+
+    println!(
+        "Updating the mirrors of {} repositories in {}",
+        repositories.len(),
+        cache_dir.display()
+    );
+
+    map_repositories(&repositories, parallel, |r, progress_bar| {
+        r.update_cache(cache_dir, progress_bar, parallel.timeout())
+    })?
+    .check()
+}
+
+/// Make the sparse checkout of every repository match the lockfile
+fn sync_sparse_checkouts(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+) -> anyhow::Result<()> {
+    // We only care about repositories that exist
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| r.exists(workspace))
+        .collect();
+
+    println!(
+        "Syncing the sparse checkouts of {} repositories",
+        repositories.len()
+    );
+
+    let changed = RelaxedCounter::new(0);
+    let failures = map_repositories(&repositories, parallel, |r, progress_bar| {
+        if r.sync_sparse_checkout(workspace, progress_bar, parallel.timeout())? {
+            changed.inc();
+        }
+        Ok(())
+    })?;
+    println!("Changed {} repositories", changed.get());
+    failures.check()
 }
 
 /// Execute a command on all our repositories
 fn execute_cmd(
     workspace: &Path,
-    threads: usize,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    format: OutputFormat,
+    output: &RunOutputOptions,
+    command: &RunCommand,
+) -> anyhow::Result<()> {
+    // We only care about repositories that exist
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| r.exists(workspace))
+        .collect();
+
+    if format == OutputFormat::Text {
+        println!("Running {} on {} repositories", command, repositories.len());
+    }
+
+    let exit_codes = Mutex::new(HashMap::new());
+    let failures = map_repositories(&repositories, parallel, |r, progress_bar| {
+        let mut log_file = match &output.log_dir {
+            Some(log_dir) => {
+                let log_path = log_dir.join(format!("{}.log", r.name()));
+                if let Some(parent) = log_path.parent() {
+                    fs_extra::dir::create_all(parent, false).with_context(|| {
+                        format!("Error creating directory {}", parent.display())
+                    })?;
+                }
+                let file = File::create(&log_path)
+                    .with_context(|| format!("Error creating {}", log_path.display()))?;
+                Some((log_path, file))
+            }
+            None => None,
+        };
+        let mut log_error = None;
+        let mut lines = vec![];
+        let mut last_line = String::new();
+
+        progress_bar.set_message(format!("{}: running...", r.name()));
+        let (cmd, args) = command.for_repository(workspace, r);
+        let exit_code = r.run_cmd(workspace, parallel.timeout(), &cmd, &args, |line| {
+            if let Some((log_path, file)) = &mut log_file {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    log_error.get_or_insert_with(|| {
+                        anyhow!(e).context(format!("Error writing to {}", log_path.display()))
+                    });
+                }
+            }
+            match output.mode {
+                RunOutput::Progress => progress_bar.set_message(format!(
+                    "{}: {}",
+                    r.name(),
+                    console::truncate_str(line.trim(), 70, "...")
+                )),
+                RunOutput::Grouped => lines.push(line.to_string()),
+                RunOutput::Prefixed => progress_bar.suspend(|| {
+                    print_output(format, &format!("{} | {}", style(r.name()).cyan(), line))
+                }),
+            }
+            if !line.trim().is_empty() {
+                last_line = line.to_string();
+            }
+        })?;
+        exit_codes
+            .lock()
+            .unwrap()
+            .insert(r.name().clone(), exit_code);
+
+        if output.mode == RunOutput::Grouped {
+            progress_bar.suspend(|| {
+                print_output(format, &style(r.name()).cyan().bold().to_string());
+                for line in &lines {
+                    print_output(format, line);
+                }
+            });
+        }
+        if let Some(e) = log_error {
+            return Err(e);
+        }
+        match exit_code {
+            Some(0) => Ok(()),
+            Some(code) if last_line.trim().is_empty() => Err(anyhow!("Exited with code {}", code)),
+            Some(code) => Err(anyhow!("Exited with code {}: {}", code, last_line.trim())),
+            None if last_line.trim().is_empty() => Err(anyhow!("Killed by a signal")),
+            None => Err(anyhow!("Killed by a signal: {}", last_line.trim())),
+        }
+    })?;
+    let exit_codes = exit_codes.into_inner().unwrap();
+
+    let records: Vec<RunRecord> = repositories
+        .iter()
+        .map(|r| {
+            let error = failures.error(r);
+            RunRecord {
+                path: r.name(),
+                exit_code: exit_codes.get(r.name()).copied().flatten(),
+                timed_out: failures.is_timed_out(r),
+                skipped: failures.is_skipped(r),
+                success: error.is_none(),
+                error,
+            }
+        })
+        .collect();
+    if format == OutputFormat::Text {
+        print_exit_code_summary(&records);
+    }
+    let columns = [
+        "path",
+        "exit_code",
+        "timed_out",
+        "skipped",
+        "success",
+        "error",
+    ];
+    print_records(format, &columns, &records)?;
+    failures.check()
+}
+
+/// Print a line of output from a command. When printing machine readable results this goes to
+/// stderr, so that stdout only contains the results.
+fn print_output(format: OutputFormat, line: &str) {
+    if format == OutputFormat::Text {
+        println!("{}", line);
+    } else {
+        eprintln!("{}", line);
+    }
+}
+
+/// The result of running a command in a repository, for machine readable output.
+#[derive(Serialize)]
+struct RunRecord<'a> {
+    path: &'a str,
+    /// None if the command could not be started or was killed by a signal
+    exit_code: Option<i32>,
+    timed_out: bool,
+    /// Whether the command wasn't run because too many other repositories failed
+    skipped: bool,
+    success: bool,
+    error: Option<String>,
+}
+
+/// Print how many repositories exited with each exit code.
+fn print_exit_code_summary(records: &[RunRecord]) {
+    let mut by_exit_code: BTreeMap<(bool, bool, Option<i32>), Vec<&str>> = BTreeMap::new();
+    for record in records {
+        by_exit_code
+            .entry((record.skipped, record.timed_out, record.exit_code))
+            .or_default()
+            .push(record.path);
+    }
+    for ((skipped, timed_out, exit_code), paths) in by_exit_code {
+        let description = match exit_code {
+            _ if skipped => style("Skipped".to_string()).yellow(),
+            _ if timed_out => style("Timed out".to_string()).red(),
+            Some(0) => style("Exit code 0".to_string()).green(),
+            Some(code) => style(format!("Exit code {}", code)).red(),
+            None => style("No exit code".to_string()).red(),
+        };
+        // Listing every successful repository isn't useful
+        if exit_code == Some(0) {
+            println!("{}: {} repositories", description, paths.len());
+        } else {
+            println!(
+                "{}: {} repositories ({})",
+                description,
+                paths.len(),
+                paths.join(", ")
+            );
+        }
+    }
+}
+
+/// Execute a command on the given repositories
+fn execute_cmd_on_repositories(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    repositories: &[Repository],
     cmd: String,
     args: Vec<String>,
 ) -> anyhow::Result<()> {
-    // Read the lockfile
-    let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
-    let repositories = lockfile.read()?;
-
     // We only care about repositories that exist
     let repos_to_fetch: Vec<Repository> = repositories
         .iter()
@@ -323,30 +1310,76 @@ fn execute_cmd(
     );
 
     // Run fetch on them
-    map_repositories(&repos_to_fetch, threads, |r, progress_bar| {
-        r.execute_cmd(workspace, progress_bar, &cmd, &args)
-    })?;
-    Ok(())
+    map_repositories(&repos_to_fetch, parallel, |r, progress_bar| {
+        r.execute_cmd(workspace, progress_bar, parallel.timeout(), &cmd, &args)
+    })?
+    .check()
 }
 
 /// Run `git fetch` on all our repositories
-fn fetch(workspace: &Path, threads: usize) -> anyhow::Result<()> {
-    let cmd = vec![
+fn fetch(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    native: bool,
+) -> anyhow::Result<()> {
+    let cmd = [
         "fetch",
         "--all",
         "--prune",
         "--recurse-submodules=on-demand",
         "--progress",
     ];
-    execute_cmd(
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| !r.skip_fetch)
+        .collect();
+    if native {
+        return fetch_native(workspace, parallel, &repositories);
+    }
+    execute_cmd_on_repositories(
         workspace,
-        threads,
+        parallel,
+        &repositories,
         "git".to_string(),
         cmd.iter().map(|s| (*s).to_string()).collect(),
     )?;
     Ok(())
 }
 
+/// Fetch all our repositories with libgit2, rather than running `git fetch` in each one
+#[cfg(feature = "native-fetch")]
+fn fetch_native(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    repositories: &[Repository],
+) -> anyhow::Result<()> {
+    // We only care about repositories that exist
+    let repos_to_fetch: Vec<Repository> = repositories
+        .iter()
+        .filter(|r| r.exists(workspace))
+        .cloned()
+        .collect();
+
+    println!("Fetching {} repositories", repos_to_fetch.len());
+
+    map_repositories(&repos_to_fetch, parallel, |r, progress_bar| {
+        r.fetch_native(workspace, progress_bar, parallel.timeout())
+    })?
+    .check()
+}
+
+#[cfg(not(feature = "native-fetch"))]
+fn fetch_native(
+    _workspace: &Path,
+    _parallel: &ParallelOptions,
+    _repositories: &[Repository],
+) -> anyhow::Result<()> {
+    Err(anyhow!(
+        "--native needs git-workspace to be built with the native-fetch feature"
+    ))
+}
+
 /// Update our lockfile
 fn lock(workspace: &Path) -> anyhow::Result<()> {
     // Find all config files
@@ -356,9 +1389,10 @@ fn lock(workspace: &Path) -> anyhow::Result<()> {
     }
     // Read the configuration sources
     let config = Config::new(config_files);
-    let sources = config
+    let contents = config
         .read()
         .with_context(|| "Error reading config files")?;
+    let sources = &contents.providers;
 
     let total_bar = ProgressBar::new(sources.len() as u64);
     total_bar.set_style(
@@ -379,22 +1413,236 @@ fn lock(workspace: &Path) -> anyhow::Result<()> {
         })
         .progress_with(total_bar)
         .collect::<anyhow::Result<Vec<_>>>()?;
-    let mut all_repositories: Vec<Repository> = results.into_iter().flatten().collect();
-    // let all_repositories: Vec<Repository> = all_repository_results.iter().collect::<anyhow::Result<Vec<Repository>>>()?;
-    // We may have duplicated repositories here. Make sure they are unique based on the full path.
-    all_repositories.sort();
-    all_repositories.dedup();
-    // Write the lockfile out
-    let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
+    // Keep track of which provider each repository came from, in the order the providers are
+    // configured. This is used to report and resolve any path collisions.
+    let mut all_repositories: Vec<(&ProviderSource, Repository)> = sources
+        .iter()
+        .zip(results)
+        .flat_map(|(source, repositories)| {
+            repositories.into_iter().map(move |mut r| {
+                r.provider = Some(source.id());
+                source.clone_options().apply(&mut r);
+                (source, r)
+            })
+        })
+        .collect();
+    // Apply any overrides in the order they are configured, so later ones take priority.
+    for repository_override in &contents.overrides {
+        let matcher = repository_override.matcher()?;
+        for (_, repo) in all_repositories.iter_mut() {
+            if matcher.is_match(repo) {
+                repository_override.apply(repo);
+            }
+        }
+    }
+    for (source, repo) in &all_repositories {
+        check_repository_path(repo.name())
+            .with_context(|| format!("Invalid path for {} from {}", repo.url(), source))?;
+    }
+    let all_repositories =
+        resolve_path_collisions(all_repositories, contents.on_collision.unwrap_or_default())?;
+    // Move any repositories that have been renamed before writing the new lockfile. If the
+    // previous lockfile can't be read, or a move fails, the lockfile is left as it is so that
+    // the checkouts aren't archived and cloned again.
+    let lockfile_path = workspace.join("workspace-lock.toml");
+    let lockfile = Lockfile::new(lockfile_path.clone());
+    if lockfile_path.exists() {
+        let previous_repositories = lockfile
+            .read()
+            .context("Error reading the previous lockfile")?;
+        move_renamed_repositories(workspace, &previous_repositories, &all_repositories)?;
+    }
     lockfile.write(&all_repositories)?;
     Ok(())
 }
 
+/// Ensure that a repository is cloned inside the workspace, and not to the workspace itself.
+/// A path template can render to an empty path, for example `{topic}` for a repository
+/// without any topics.
+fn check_repository_path(path: &str) -> anyhow::Result<()> {
+    // Paths use the platform's separator, see `Repository::new`
+    let parts = || path.split(['/', std::path::MAIN_SEPARATOR]);
+    if parts().all(str::is_empty) {
+        return Err(anyhow!("The path is empty"));
+    }
+    if parts().any(|part| part == "." || part == ".." || part.is_empty()) {
+        return Err(anyhow!(
+            "The path {} contains an empty, . or .. directory",
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Move the checkouts of any repositories that have been renamed or transferred since the
+/// lockfile was last written, rather than archiving them and cloning them again. This keeps
+/// any local branches and stashes.
+fn move_renamed_repositories(
+    workspace: &Path,
+    previous: &[Repository],
+    current: &[Repository],
+) -> anyhow::Result<()> {
+    let mut failed = 0;
+    let previous_by_id: HashMap<&str, &Repository> = previous
+        .iter()
+        .filter_map(|r| r.id.as_deref().map(|id| (id, r)))
+        .collect();
+
+    for repo in current {
+        let previous_repo = match repo.id.as_deref().and_then(|id| previous_by_id.get(id)) {
+            Some(previous_repo) => previous_repo,
+            None => continue,
+        };
+        if previous_repo.name() == repo.name() || !previous_repo.exists(workspace) {
+            continue;
+        }
+        match repo.move_from(workspace, previous_repo.name()) {
+            Ok(_) => println!(
+                "Moved renamed repository {} to {}",
+                style(previous_repo.name()).yellow(),
+                style(repo.name()).green()
+            ),
+            Err(e) => {
+                failed += 1;
+                eprintln!(
+                    "{} {} to {}: {:#}",
+                    style("Error moving renamed repository").red(),
+                    style(previous_repo.name()).yellow(),
+                    style(repo.name()).green(),
+                    e
+                )
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "Error moving {} renamed repositories, the lockfile has not been updated",
+            failed
+        );
+    }
+    Ok(())
+}
+
+/// Ensure that no two repositories with different URLs will be cloned to the same path. This
+/// can happen when different providers, or path templates, produce the same path. The same
+/// repository may also be returned by more than one provider, which is not a collision.
+fn resolve_path_collisions(
+    repositories: Vec<(&ProviderSource, Repository)>,
+    strategy: CollisionStrategy,
+) -> anyhow::Result<Vec<Repository>> {
+    let mut by_path: BTreeMap<String, Vec<(&ProviderSource, Repository)>> = BTreeMap::new();
+    for (source, repo) in repositories {
+        let entries = by_path.entry(repo.name().clone()).or_default();
+        if !entries.iter().any(|(_, r)| r.url() == repo.url()) {
+            entries.push((source, repo));
+        }
+    }
+
+    let mut resolved = vec![];
+    let mut total_collisions = 0;
+    for (path, mut entries) in by_path {
+        if entries.len() == 1 {
+            resolved.extend(entries.into_iter().map(|(_, r)| r));
+            continue;
+        }
+        total_collisions += 1;
+        eprintln!(
+            "{} is used by {} repositories:",
+            style(&path).yellow(),
+            entries.len()
+        );
+        for (source, repo) in &entries {
+            eprintln!("  {} from {}", style(repo.url()).red(), source);
+        }
+        match strategy {
+            CollisionStrategy::Error => continue,
+            CollisionStrategy::FirstWins => {
+                eprintln!("  Using {}", style(entries[0].1.url()).green());
+                entries.truncate(1);
+            }
+            CollisionStrategy::SuffixHost => {
+                for (_, repo) in entries.iter_mut().skip(1) {
+                    let new_path = format!("{}-{}", path, host_from_url(repo.url()));
+                    eprintln!(
+                        "  Cloning {} to {}",
+                        style(repo.url()).green(),
+                        style(&new_path).green()
+                    );
+                    repo.set_path(new_path);
+                }
+            }
+        }
+        resolved.extend(entries.into_iter().map(|(_, r)| r));
+    }
+
+    if strategy == CollisionStrategy::Error && total_collisions > 0 {
+        return Err(anyhow!(
+            "{} paths are used by more than one repository. Check your path templates, or set \
+             on_collision to \"first-wins\" or \"suffix-host\" in your configuration.",
+            total_collisions
+        ));
+    }
+
+    // Adding a suffix could itself cause a collision, if two repositories on the same host
+    // produced the same path.
+    let unique_paths: HashSet<&String> = resolved.iter().map(|r| r.name()).collect();
+    if unique_paths.len() != resolved.len() {
+        return Err(anyhow!(
+            "Paths are still used by more than one repository after adding the host as a suffix"
+        ));
+    }
+
+    Ok(resolved)
+}
+
+/// A repository in the lockfile, for machine readable output.
+#[derive(Serialize)]
+struct ListRecord<'a> {
+    path: &'a str,
+    absolute_path: PathBuf,
+    url: &'a str,
+    branch: Option<&'a str>,
+    upstream: Option<&'a str>,
+    provider: Option<&'a str>,
+    topics: &'a [String],
+    exists: bool,
+}
+
 /// List the contents of our workspace
-fn list(workspace: &Path, full: bool) -> anyhow::Result<()> {
+fn list(
+    workspace: &Path,
+    full: bool,
+    format: OutputFormat,
+    filter: &RepositoryFilter,
+) -> anyhow::Result<()> {
     // Read and parse the lockfile
-    let lockfile = Lockfile::new(workspace.join("workspace-lock.toml"));
-    let repositories = lockfile.read().context("Error reading lockfile")?;
+    let repositories = read_repositories(workspace, filter)?;
+    if format != OutputFormat::Text {
+        let records: Vec<ListRecord> = repositories
+            .iter()
+            .map(|r| ListRecord {
+                path: r.name(),
+                absolute_path: workspace.join(r.name()),
+                url: r.url(),
+                branch: r.branch.as_deref(),
+                upstream: r.upstream.as_deref(),
+                provider: r.provider.as_deref(),
+                topics: &r.topics,
+                exists: r.exists(workspace),
+            })
+            .collect();
+        let columns = [
+            "path",
+            "absolute_path",
+            "url",
+            "branch",
+            "upstream",
+            "provider",
+            "topics",
+            "exists",
+        ];
+        return print_records(format, &columns, &records);
+    }
     let existing_repositories = repositories.iter().filter(|r| r.exists(workspace));
     for repo in existing_repositories {
         if full {
@@ -406,10 +1654,146 @@ fn list(workspace: &Path, full: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The status of a repository, for machine readable output.
+#[derive(Serialize)]
+struct StatusRecord<'a> {
+    path: &'a str,
+    primary_branch: Option<&'a str>,
+    dirty: bool,
+    #[serde(flatten)]
+    status: &'a RepositoryStatus,
+}
+
+/// Show the status of every repository in a table
+fn status(
+    workspace: &Path,
+    parallel: &ParallelOptions,
+    filter: &RepositoryFilter,
+    only_dirty: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let repositories: Vec<Repository> = read_repositories(workspace, filter)?
+        .into_iter()
+        .filter(|r| r.exists(workspace))
+        .collect();
+
+    let statuses = Mutex::new(vec![]);
+    let failures = map_repositories(&repositories, parallel, |r, _| {
+        let status = r.status(workspace)?;
+        statuses.lock().unwrap().push((r.to_owned(), status));
+        Ok(())
+    })?;
+    let mut statuses = statuses.into_inner().unwrap();
+    statuses.retain(|(_, status)| !only_dirty || status.is_dirty());
+    statuses.sort_by(|(a, _), (b, _)| a.name().cmp(b.name()));
+
+    if format != OutputFormat::Text {
+        let records: Vec<StatusRecord> = statuses
+            .iter()
+            .map(|(r, status)| StatusRecord {
+                path: r.name(),
+                primary_branch: r.branch.as_deref(),
+                dirty: status.is_dirty(),
+                status,
+            })
+            .collect();
+        let columns = [
+            "path",
+            "branch",
+            "primary_branch",
+            "detached",
+            "changed",
+            "untracked",
+            "ahead",
+            "behind",
+            "stashes",
+            "dirty",
+        ];
+        print_records(format, &columns, &records)?;
+        return failures.check();
+    }
+
+    let count = |n: usize| if n == 0 { String::new() } else { n.to_string() };
+    let rows: Vec<[String; 7]> = statuses
+        .iter()
+        .map(|(r, status)| {
+            let branch = match (&status.branch, &r.branch) {
+                _ if status.detached => "(detached)".to_string(),
+                (Some(current), Some(primary)) if current != primary => {
+                    format!("{} ({})", current, primary)
+                }
+                (Some(current), _) => current.clone(),
+                (None, _) => String::new(),
+            };
+            [
+                r.name().clone(),
+                branch,
+                count(status.changed),
+                count(status.untracked),
+                count(status.ahead),
+                count(status.behind),
+                count(status.stashes),
+            ]
+        })
+        .collect();
+
+    let headers = [
+        "Repository",
+        "Branch",
+        "Changed",
+        "Untracked",
+        "Ahead",
+        "Behind",
+        "Stashes",
+    ];
+    let mut widths = headers.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header: Vec<String> = headers
+        .iter()
+        .zip(widths)
+        .map(|(header, width)| format!("{:<width$}", header))
+        .collect();
+    println!("{}", style(header.join("  ").trim_end()).bold());
+    for ((repo, status), row) in statuses.iter().zip(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .enumerate()
+            .map(|(idx, (cell, width))| {
+                let cell = format!("{:<width$}", cell);
+                match idx {
+                    1 if !status.on_branch(repo.branch.as_deref()) => {
+                        style(cell).yellow().to_string()
+                    }
+                    2 | 3 | 4 | 6 => style(cell).red().to_string(),
+                    5 => style(cell).cyan().to_string(),
+                    _ => cell,
+                }
+            })
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    println!(
+        "{} of {} repositories have local changes",
+        statuses.iter().filter(|(_, s)| s.is_dirty()).count(),
+        repositories.len()
+    );
+    failures.check()
+}
+
 /// Take any number of repositories and apply `f` on each one.
 /// This method takes care of displaying progress bars and displaying
-/// any errors that may arise.
-fn map_repositories<F>(repositories: &[Repository], threads: usize, f: F) -> anyhow::Result<()>
+/// any errors that may arise. The repositories that failed are returned with their errors.
+/// Once `parallel` allows no more failures, repositories that haven't started are skipped.
+fn map_repositories<'a, F>(
+    repositories: &'a [Repository],
+    parallel: &ParallelOptions,
+    f: F,
+) -> anyhow::Result<Failures<'a>>
 where
     F: Fn(&Repository, &ProgressBar) -> anyhow::Result<()> + std::marker::Sync,
 {
@@ -430,20 +1814,26 @@ where
     // Use a counter here if there is no tty, to show a stream of progress messages rather than
     // a dynamic progress bar.
     let counter = RelaxedCounter::new(1);
+    // Count failures so that we can stop starting new repositories once there are too many.
+    let failure_count = RelaxedCounter::new(0);
+    let max_failures = parallel.max_failures();
 
     // Create our thread pool. We do this rather than use `.par_iter()` on any iterable as it
     // allows us to customize the number of threads.
     let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
+        .num_threads(parallel.threads)
         .build()
         .with_context(|| "Error creating the thread pool")?;
 
     // pool.install means that `.par_iter()` will use the thread pool we've built above.
-    let errors: Vec<(&Repository, anyhow::Error)> = pool.install(|| {
+    let results: Vec<(&Repository, Option<anyhow::Error>)> = pool.install(|| {
         repositories
             .par_iter()
             // Update our progress bar with each iteration
             .map(|repo| {
+                if max_failures.is_some_and(|max| failure_count.get() >= max) {
+                    return Err((repo, None));
+                }
                 // Create a progress bar and configure some defaults
                 let progress_bar = progress.add(ProgressBar::new_spinner());
                 progress_bar.set_message("waiting...");
@@ -451,16 +1841,34 @@ where
                 // Increment our counter for use if the console is not a tty.
                 let idx = counter.inc();
                 if !is_attended {
-                    println!("[{}/{}] Starting {}", idx, total_repositories, repo.name());
+                    eprintln!("[{}/{}] Starting {}", idx, total_repositories, repo.name());
                 }
-                // Run our given function. If the result is an error then attach the
-                // erroring Repository object to it.
-                let result = match f(repo, &progress_bar) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err((repo, e)),
+                // Run our given function, retrying it if it fails with a network error. If the
+                // result is an error then attach the erroring Repository object to it.
+                let mut attempt = 0;
+                let result = loop {
+                    match f(repo, &progress_bar) {
+                        Ok(_) => break Ok(()),
+                        Err(e) if attempt < parallel.retries && is_transient(&e) => {
+                            attempt += 1;
+                            // Wait twice as long before each attempt, up to about a minute
+                            let delay = Duration::from_secs(2u64.pow(attempt.min(6)));
+                            progress_bar.set_message(format!(
+                                "{}: retrying in {}s after {:#}",
+                                repo.name(),
+                                delay.as_secs(),
+                                e
+                            ));
+                            thread::sleep(delay);
+                        }
+                        Err(e) => {
+                            failure_count.inc();
+                            break Err((repo, Some(e)));
+                        }
+                    }
                 };
                 if !is_attended {
-                    println!("[{}/{}] Finished {}", idx, total_repositories, repo.name());
+                    eprintln!("[{}/{}] Finished {}", idx, total_repositories, repo.name());
                 }
                 // Clear the progress bar and return the result
                 progress_bar.finish_and_clear();
@@ -473,20 +1881,158 @@ where
             .collect()
     });
 
+    let mut failures = Failures::default();
+    for (repo, error) in results {
+        match error {
+            Some(error) if timeout_of(&error).is_some() => failures.timed_out.push((repo, error)),
+            Some(error) => failures.failed.push((repo, error)),
+            None => failures.skipped.push(repo),
+        }
+    }
+
     // Print out each repository that failed to run.
-    if !errors.is_empty() {
-        eprintln!("{} repositories failed:", errors.len());
-        for (repo, error) in errors {
+    if !failures.failed.is_empty() {
+        eprintln!("{} repositories failed:", failures.failed.len());
+        for (repo, error) in &failures.failed {
             eprintln!("{}:", repo.name());
             error
                 .chain()
                 .for_each(|cause| eprintln!("because: {}", cause));
         }
     }
+    // Timeouts have the same cause, so they are listed together.
+    if let Some(timeout) = failures.timed_out.first().and_then(|(_, e)| timeout_of(e)) {
+        let names: Vec<&str> = failures
+            .timed_out
+            .iter()
+            .map(|(repo, _)| repo.name().as_str())
+            .collect();
+        eprintln!(
+            "{} repositories timed out after {} seconds: {}",
+            names.len(),
+            timeout.as_secs(),
+            names.join(", ")
+        );
+    }
+    if !failures.skipped.is_empty() {
+        eprintln!(
+            "Skipped {} repositories after {} failures",
+            failures.skipped.len(),
+            failures.failed.len() + failures.timed_out.len()
+        );
+    }
+
+    Ok(failures)
+}
+
+/// Whether `error` is a network problem that may go away if the command is run again.
+fn is_transient(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<CommandError>(),
+        Some(CommandError::Transient(_))
+    )
+}
+
+/// The timeout that `error` was caused by, if it was caused by one.
+fn timeout_of(error: &anyhow::Error) -> Option<Duration> {
+    match error.downcast_ref::<CommandError>() {
+        Some(CommandError::TimedOut(timeout)) => Some(*timeout),
+        _ => None,
+    }
+}
+
+/// The repositories that `map_repositories` failed on, or skipped because too many others
+/// failed.
+#[derive(Default)]
+struct Failures<'a> {
+    failed: Vec<(&'a Repository, anyhow::Error)>,
+    timed_out: Vec<(&'a Repository, anyhow::Error)>,
+    skipped: Vec<&'a Repository>,
+}
+
+impl Failures<'_> {
+    /// A description of why `repository` failed or was skipped, or None if it succeeded.
+    fn error(&self, repository: &Repository) -> Option<String> {
+        if let Some((_, e)) = self
+            .failed
+            .iter()
+            .chain(&self.timed_out)
+            .find(|(failed, _)| failed.name() == repository.name())
+        {
+            Some(format!("{:#}", e))
+        } else if self.is_skipped(repository) {
+            Some("Skipped after too many failures".to_string())
+        } else {
+            None
+        }
+    }
 
-    Ok(())
+    fn is_timed_out(&self, repository: &Repository) -> bool {
+        self.timed_out
+            .iter()
+            .any(|(timed_out, _)| timed_out.name() == repository.name())
+    }
+
+    fn is_skipped(&self, repository: &Repository) -> bool {
+        self.skipped
+            .iter()
+            .any(|skipped| skipped.name() == repository.name())
+    }
+
+    /// An error if any repository failed or was skipped, so that the process exits non-zero.
+    fn check(&self) -> anyhow::Result<()> {
+        if self.failed.is_empty() && self.timed_out.is_empty() && self.skipped.is_empty() {
+            return Ok(());
+        }
+        Err(RepositoriesFailed {
+            failed: self.failed.len(),
+            timed_out: self.timed_out.len(),
+            skipped: self.skipped.len(),
+        }
+        .into())
+    }
+}
+
+/// The error returned when a command failed in some repositories. The process exits with
+/// `RepositoriesFailed::EXIT_CODE` rather than 1, so that scripts can tell this apart from the
+/// command not running at all.
+#[derive(Debug)]
+struct RepositoriesFailed {
+    failed: usize,
+    timed_out: usize,
+    skipped: usize,
+}
+
+impl RepositoriesFailed {
+    const EXIT_CODE: i32 = 2;
+}
+
+impl fmt::Display for RepositoriesFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<String> = [
+            (self.failed, "failed"),
+            (self.timed_out, "timed out"),
+            (self.skipped, "were skipped"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .enumerate()
+        .map(|(idx, (count, what))| match idx {
+            0 => format!("{} repositories {}", count, what),
+            _ => format!("{} {}", count, what),
+        })
+        .collect();
+        match parts.split_last() {
+            Some((last, rest)) if !rest.is_empty() => {
+                write!(f, "{} and {}", rest.join(", "), last)
+            }
+            _ => write!(f, "{}", parts.join("")),
+        }
+    }
 }
 
+impl std::error::Error for RepositoriesFailed {}
+
 fn archive_repositories(to_archive: Vec<(PathBuf, PathBuf)>) -> anyhow::Result<()> {
     println!("Archiving {} repositories", to_archive.len());
     for (from_dir, to_dir) in to_archive.into_iter() {
@@ -606,3 +2152,264 @@ fn get_all_repositories_to_archive(
 
     Ok(to_archive)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn shell_placeholders_are_not_executed() {
+        let workspace = std::env::temp_dir().join(format!("git-workspace-test-{}", process::id()));
+        let branch = "x;touch${IFS}PWNED;";
+        let repository = Repository::new(
+            "repo".to_string(),
+            "git@github.com:owner/repo.git".to_string(),
+            Some(branch.to_string()),
+            None,
+            None,
+            vec![],
+        );
+        fs::create_dir_all(workspace.join("repo")).unwrap();
+
+        let command = RunCommand {
+            shell: true,
+            command: vec!["echo {branch}".to_string()],
+        };
+        let (cmd, args) = command.for_repository(&workspace, &repository);
+        assert_eq!(args, ["-c", "echo \"$GW_REPO_BRANCH\""]);
+        let mut output = vec![];
+        let exit_code = repository
+            .run_cmd(&workspace, None, &cmd, &args, |line| {
+                output.push(line.to_string())
+            })
+            .unwrap();
+        let pwned = workspace.join("repo").join("PWNED").exists();
+        fs::remove_dir_all(&workspace).unwrap();
+
+        assert_eq!(exit_code, Some(0));
+        assert_eq!(output, [branch]);
+        assert!(!pwned, "the branch name was run as a command");
+    }
+
+    fn run_command(args: &[&str]) -> Vec<String> {
+        let args = Args::from_iter_safe(["git-workspace", "-w", "/tmp", "run"].iter().chain(args));
+        match args.map(|args| args.command) {
+            Ok(Command::Run { command, .. }) => command.command,
+            Ok(_) => panic!("not parsed as run"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn provider(args: &[&str]) -> ProviderSource {
+        ProviderSource::from_iter_safe(["add"].iter().chain(args)).unwrap()
+    }
+
+    fn repository(path: &str, url: &str) -> Repository {
+        Repository::new(path.to_string(), url.to_string(), None, None, None, vec![])
+    }
+
+    fn paths(repositories: &[Repository]) -> Vec<(&str, &str)> {
+        repositories
+            .iter()
+            .map(|r| (r.name().as_str(), r.url()))
+            .collect()
+    }
+
+    #[test]
+    fn path_collisions_are_errors_by_default() {
+        let github = provider(&["github", "octocat"]);
+        let gitlab = provider(&["gitlab", "octocat"]);
+        let repositories = vec![
+            (
+                &github,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+            (
+                &gitlab,
+                repository("code/tool", "git@gitlab.com:octocat/tool.git"),
+            ),
+            (
+                &github,
+                repository("code/other", "git@github.com:octocat/other.git"),
+            ),
+        ];
+        let error = resolve_path_collisions(repositories, CollisionStrategy::Error).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("1 paths are used by more than one"));
+    }
+
+    #[test]
+    fn path_collisions_keep_the_first_provider() {
+        let github = provider(&["github", "octocat"]);
+        let gitlab = provider(&["gitlab", "octocat"]);
+        let repositories = vec![
+            (
+                &gitlab,
+                repository("code/tool", "git@gitlab.com:octocat/tool.git"),
+            ),
+            (
+                &github,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+        ];
+        let resolved = resolve_path_collisions(repositories, CollisionStrategy::FirstWins).unwrap();
+        assert_eq!(
+            paths(&resolved),
+            [("code/tool", "git@gitlab.com:octocat/tool.git")]
+        );
+    }
+
+    #[test]
+    fn path_collisions_add_the_host_as_a_suffix() {
+        let github = provider(&["github", "octocat"]);
+        let gitlab = provider(&["gitlab", "octocat"]);
+        let repositories = vec![
+            (
+                &github,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+            (
+                &gitlab,
+                repository("code/tool", "git@gitlab.com:octocat/tool.git"),
+            ),
+        ];
+        let resolved =
+            resolve_path_collisions(repositories, CollisionStrategy::SuffixHost).unwrap();
+        assert_eq!(
+            paths(&resolved),
+            [
+                ("code/tool", "git@github.com:octocat/tool.git"),
+                ("code/tool-gitlab.com", "git@gitlab.com:octocat/tool.git"),
+            ]
+        );
+    }
+
+    #[test]
+    fn path_collisions_on_the_same_host_are_still_errors() {
+        let github = provider(&["github", "octocat"]);
+        let gitlab = provider(&["gitlab", "octocat"]);
+        let other_gitlab = provider(&["gitlab", "octo-org"]);
+        let repositories = vec![
+            (
+                &github,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+            (
+                &gitlab,
+                repository("code/tool", "git@gitlab.com:octocat/tool.git"),
+            ),
+            (
+                &other_gitlab,
+                repository("code/tool", "git@gitlab.com:octo-org/tool.git"),
+            ),
+        ];
+        let error =
+            resolve_path_collisions(repositories, CollisionStrategy::SuffixHost).unwrap_err();
+        assert!(error.to_string().starts_with("Paths are still used"));
+    }
+
+    #[test]
+    fn the_same_repository_from_two_providers_is_not_a_collision() {
+        let user = provider(&["github", "octocat"]);
+        let team = provider(&["github", "octo-org", "--team", "tools"]);
+        let repositories = vec![
+            (
+                &user,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+            (
+                &team,
+                repository("code/tool", "git@github.com:octocat/tool.git"),
+            ),
+        ];
+        let resolved = resolve_path_collisions(repositories, CollisionStrategy::Error).unwrap();
+        assert_eq!(
+            paths(&resolved),
+            [("code/tool", "git@github.com:octocat/tool.git")]
+        );
+    }
+
+    #[test]
+    fn repository_paths_stay_inside_the_workspace() {
+        assert!(check_repository_path("github/owner/name").is_ok());
+        assert!(check_repository_path("github/.dotfiles").is_ok());
+        for path in ["", "/", "..", "github/../name", "./name", "github//name"] {
+            assert!(
+                check_repository_path(path).is_err(),
+                "{:?} was allowed",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn workspace_failures_are_combined() {
+        assert!(workspaces_failed(vec![]).is_ok());
+
+        let repositories_failed = |failed, timed_out| {
+            anyhow::Error::new(RepositoriesFailed {
+                failed,
+                timed_out,
+                skipped: 0,
+            })
+            .context("Error in workspace")
+        };
+        let error = workspaces_failed(vec![
+            ("work", repositories_failed(1, 0)),
+            ("personal", repositories_failed(2, 1)),
+        ])
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Errors in workspaces work, personal");
+        let total = error.downcast_ref::<RepositoriesFailed>().unwrap();
+        assert_eq!((total.failed, total.timed_out), (3, 1));
+
+        // Any other error means the command didn't run, which takes priority
+        let error = workspaces_failed(vec![
+            ("work", repositories_failed(1, 0)),
+            ("personal", anyhow!("No configuration files found")),
+        ])
+        .unwrap_err();
+        assert!(error.downcast_ref::<RepositoriesFailed>().is_none());
+    }
+
+    #[test]
+    fn max_failures_must_be_positive() {
+        let parse = |max_failures| {
+            Args::from_iter_safe([
+                "git-workspace",
+                "-w",
+                "/tmp",
+                "fetch",
+                "--max-failures",
+                max_failures,
+            ])
+        };
+        assert!(parse("0").is_err());
+        match parse("2").unwrap().command {
+            Command::Fetch { parallel, .. } => assert_eq!(parallel.max_failures(), Some(2)),
+            _ => panic!("not parsed as fetch"),
+        }
+    }
+
+    #[test]
+    fn run_passes_on_everything_after_the_command() {
+        assert_eq!(run_command(&["ls", "-t"]), ["ls", "-t"]);
+        assert_eq!(
+            run_command(&["rg", "-g", "*.rs", "foo"]),
+            ["rg", "-g", "*.rs", "foo"]
+        );
+        assert_eq!(
+            run_command(&["-t", "2", "echo", "--output", "prefixed"]),
+            ["echo", "--output", "prefixed"]
+        );
+        assert_eq!(run_command(&["--", "-x"]), ["-x"]);
+    }
+
+    #[test]
+    fn run_rejects_unknown_options_before_the_command() {
+        let args = ["git-workspace", "-w", "/tmp", "run", "--shel", "ls"];
+        assert!(Args::from_iter_safe(args).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a command prints its results.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Output for people to read
+    #[default]
+    Text,
+    /// A single JSON array
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Tab separated values, with a header row
+    Tsv,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &["text", "json", "ndjson", "tsv"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "tsv" => Ok(Self::Tsv),
+            _ => Err(anyhow!(
+                "Unknown format {}, expected one of {}",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Text => "text",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Tsv => "tsv",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How `git workspace run` shows the output of the command in each repository.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum RunOutput {
+    /// Show the last line of output from each repository next to its progress bar
+    #[default]
+    Progress,
+    /// Print all the output of each repository in a block once it finishes
+    Grouped,
+    /// Print each line as it arrives, prefixed with the repository
+    Prefixed,
+}
+
+impl RunOutput {
+    pub const VARIANTS: &'static [&'static str] = &["progress", "grouped", "prefixed"];
+}
+
+impl FromStr for RunOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "progress" => Ok(Self::Progress),
+            "grouped" => Ok(Self::Grouped),
+            "prefixed" => Ok(Self::Prefixed),
+            _ => Err(anyhow!(
+                "Unknown output {}, expected one of {}",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Print `records` in a machine readable format. `columns` are the fields of each record to
+/// include in TSV output, in order. Text output is left to the caller.
+pub fn print_records<T: Serialize>(
+    format: OutputFormat,
+    columns: &[&str],
+    records: &[T],
+) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        OutputFormat::Ndjson => {
+            for record in records {
+                println!("{}", serde_json::to_string(record)?);
+            }
+        }
+        OutputFormat::Tsv => {
+            println!("{}", columns.join("\t"));
+            for record in records {
+                let value = serde_json::to_value(record)?;
+                let row: Vec<String> = columns
+                    .iter()
+                    .map(|column| match &value[column] {
+                        serde_json::Value::Null => String::new(),
+                        serde_json::Value::String(s) => s.clone(),
+                        serde_json::Value::Array(items) => items
+                            .iter()
+                            .map(|item| item.as_str().map_or(item.to_string(), str::to_string))
+                            .collect::<Vec<_>>()
+                            .join(","),
+                        other => other.to_string(),
+                    })
+                    // Tabs and newlines would break the row apart
+                    .map(|cell| cell.replace(['\t', '\n'], " "))
+                    .collect();
+                println!("{}", row.join("\t"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,17 @@
-use crate::providers::{create_exclude_regex_set, Provider, APP_USER_AGENT};
-use crate::repository::Repository;
-use anyhow::{bail, Context};
+use crate::providers::{
+    create_exclude_regex_set, parse_path_template, PathTemplate, Provider, TemplateContext,
+    APP_USER_AGENT,
+};
+use crate::repository::{CloneOptions, Repository};
+use anyhow::{anyhow, bail, Context};
 use console::style;
 use graphql_client::{GraphQLQuery, Response};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fmt;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 // See https://github.com/graphql-rust/graphql-client/blob/master/graphql_client/tests/custom_scalars.rs#L6
@@ -19,6 +25,177 @@ type GitSSHRemote = String;
 )]
 pub struct Repositories;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/providers/graphql/github/schema.graphql",
+    query_path = "src/providers/graphql/github/starred.graphql",
+    response_derives = "Debug"
+)]
+pub struct StarredRepositories;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/providers/graphql/github/schema.graphql",
+    query_path = "src/providers/graphql/github/watching.graphql",
+    response_derives = "Debug"
+)]
+pub struct WatchedRepositories;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/providers/graphql/github/schema.graphql",
+    query_path = "src/providers/graphql/github/collaborator.graphql",
+    response_derives = "Debug"
+)]
+pub struct CollaboratorRepositories;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/providers/graphql/github/schema.graphql",
+    query_path = "src/providers/graphql/github/team.graphql",
+    response_derives = "Debug"
+)]
+pub struct TeamRepositories;
+
+// Each query generates its own set of types for the repository nodes, even though they all
+// select the same fields. We convert them into this struct so the rest of the code can
+// treat them the same.
+struct RepositoryNode {
+    id: String,
+    name_with_owner: String,
+    ssh_url: String,
+    is_archived: bool,
+    is_fork: bool,
+    topics: Vec<String>,
+    default_branch: Option<String>,
+    upstream: Option<String>,
+}
+
+macro_rules! impl_repository_node {
+    ($node:ty) => {
+        impl From<$node> for RepositoryNode {
+            fn from(item: $node) -> Self {
+                Self {
+                    id: item.id,
+                    name_with_owner: item.name_with_owner,
+                    ssh_url: item.ssh_url,
+                    is_archived: item.is_archived,
+                    is_fork: item.is_fork,
+                    topics: item
+                        .repository_topics
+                        .nodes
+                        .unwrap_or_default()
+                        .into_iter()
+                        .flatten()
+                        .map(|node| node.topic.name)
+                        .collect(),
+                    default_branch: item.default_branch_ref.map(|branch| branch.name),
+                    upstream: item.parent.map(|parent| parent.ssh_url),
+                }
+            }
+        }
+    };
+}
+
+impl_repository_node!(repositories::RepositoriesRepositoryOwnerRepositoriesNodes);
+impl_repository_node!(starred_repositories::StarredRepositoriesUserStarredRepositoriesNodes);
+impl_repository_node!(watched_repositories::WatchedRepositoriesUserWatchingNodes);
+impl_repository_node!(collaborator_repositories::CollaboratorRepositoriesUserRepositoriesNodes);
+impl_repository_node!(team_repositories::TeamRepositoriesOrganizationTeamRepositoriesNodes);
+
+/// A single page of repositories returned from one of our queries.
+struct RepositoryPage {
+    nodes: Vec<RepositoryNode>,
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+impl RepositoryPage {
+    fn new<T: Into<RepositoryNode>>(
+        nodes: Option<Vec<Option<T>>>,
+        has_next_page: bool,
+        end_cursor: Option<String>,
+    ) -> Self {
+        Self {
+            nodes: nodes
+                .unwrap_or_default()
+                .into_iter()
+                .flatten()
+                .map(Into::into)
+                .collect(),
+            has_next_page,
+            end_cursor,
+        }
+    }
+}
+
+/// The response to a query for the user that owns a token.
+#[derive(Deserialize)]
+struct ViewerData {
+    viewer: Viewer,
+}
+
+#[derive(Deserialize)]
+struct Viewer {
+    login: String,
+}
+
+/// Which set of repositories a Github provider should clone.
+#[derive(
+    Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum GithubSourceKind {
+    /// Repositories owned by the user or organisation
+    #[default]
+    Owner,
+    /// Repositories the user has starred
+    Starred,
+    /// Repositories the user is watching
+    Watching,
+    /// Repositories the user is a collaborator on
+    Collaborator,
+    /// Repositories belonging to a team within the organisation
+    Team,
+}
+
+impl GithubSourceKind {
+    const VARIANTS: &'static [&'static str] =
+        &["owner", "starred", "watching", "collaborator", "team"];
+}
+
+impl FromStr for GithubSourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Self::Owner),
+            "starred" => Ok(Self::Starred),
+            "watching" => Ok(Self::Watching),
+            "collaborator" => Ok(Self::Collaborator),
+            "team" => Ok(Self::Team),
+            _ => Err(anyhow!(
+                "Unknown kind {}, expected one of {}",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GithubSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Owner => "owner",
+            Self::Starred => "starred",
+            Self::Watching => "watching",
+            Self::Collaborator => "collaborator",
+            Self::Team => "team",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 fn default_env_var() -> String {
     String::from("GITHUB_TOKEN")
 }
@@ -27,21 +204,37 @@ const fn default_forks() -> bool {
     false
 }
 
-static DEFAULT_GITHUB_URL: &str = "https://api.github.com/graphql";
+pub static DEFAULT_GITHUB_URL: &str = "https://api.github.com/graphql";
 
 fn public_github_url() -> String {
     DEFAULT_GITHUB_URL.to_string()
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, Ord, PartialEq, PartialOrd, StructOpt)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Eq, Ord, PartialEq, PartialOrd, StructOpt)]
 #[serde(rename_all = "lowercase")]
 #[structopt(about = "Add a Github user or organization by name")]
 pub struct GithubProvider {
     /// The name of the user or organisation to add.
     pub name: String,
+    #[structopt(long = "kind", default_value = "owner", possible_values = GithubSourceKind::VARIANTS)]
+    #[serde(default)]
+    /// Which repositories to clone: those owned by the user or organisation, those the user
+    /// has starred, is watching or is a collaborator on, or those belonging to a team
+    /// within the organisation
+    kind: GithubSourceKind,
+    #[structopt(long = "team")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The slug of the organisation team to add. Required when the kind is "team".
+    team: Option<String>,
     #[structopt(long = "path", default_value = "github")]
     /// Clone repositories to a specific base path
     path: String,
+    #[structopt(long = "path-template")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Clone repositories to a path built from a template, like "{host}/{owner}/{name}".
+    /// Supports {path}, {host}, {owner}, {name}, {group_path} and {topic}, optionally with
+    /// a case transform like {owner:lower} or {name:upper}.
+    path_template: Option<String>,
     #[structopt(long = "env-name", short = "e", default_value = "GITHUB_TOKEN")]
     #[serde(default = "default_env_var")]
     /// Environment variable containing the auth token
@@ -63,39 +256,248 @@ pub struct GithubProvider {
     /// Github instance URL, if using Github Enterprise this should be
     /// http(s)://HOSTNAME/api/graphql
     pub url: String,
+
+    #[structopt(flatten)]
+    #[serde(flatten)]
+    pub clone_options: CloneOptions,
 }
 
 impl fmt::Display for GithubProvider {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Github user/org {} in directory {}, using the token stored in {}",
-            style(&self.name.to_lowercase()).green(),
-            style(&self.path.to_lowercase()).green(),
-            style(&self.env_var).green(),
-        )
+        match (self.kind, &self.team) {
+            (GithubSourceKind::Owner, _) => write!(
+                f,
+                "Github user/org {} in directory {}, using the token stored in {}",
+                style(&self.name.to_lowercase()).green(),
+                style(&self.path.to_lowercase()).green(),
+                style(&self.env_var).green(),
+            ),
+            (GithubSourceKind::Team, Some(team)) => write!(
+                f,
+                "Github team {}/{} in directory {}, using the token stored in {}",
+                style(&self.name.to_lowercase()).green(),
+                style(team.to_lowercase()).green(),
+                style(&self.path.to_lowercase()).green(),
+                style(&self.env_var).green(),
+            ),
+            (kind, _) => write!(
+                f,
+                "Github {} repositories of user {} in directory {}, using the token stored in {}",
+                style(kind).green(),
+                style(&self.name.to_lowercase()).green(),
+                style(&self.path.to_lowercase()).green(),
+                style(&self.env_var).green(),
+            ),
+        }
     }
 }
 
 impl GithubProvider {
-    fn parse_repo(
-        &self,
-        path: &str,
-        repo: &repositories::RepositoriesRepositoryOwnerRepositoriesNodes,
-    ) -> Repository {
-        let default_branch = repo
-            .default_branch_ref
-            .as_ref()
-            .map(|branch| branch.name.clone());
-        let upstream = repo.parent.as_ref().map(|parent| parent.ssh_url.clone());
-
+    fn parse_repo(&self, template: Option<&PathTemplate>, repo: RepositoryNode) -> Repository {
+        let path = match template {
+            Some(template) => template.render(&TemplateContext {
+                path: &self.path,
+                url: &repo.ssh_url,
+                full_path: &repo.name_with_owner,
+                topics: &repo.topics,
+            }),
+            None => format!("{}/{}", self.path, repo.name_with_owner),
+        };
         Repository::new(
-            format!("{}/{}", path, repo.name_with_owner.clone()),
-            repo.ssh_url.clone(),
-            default_branch,
-            upstream,
+            path,
+            repo.ssh_url,
+            repo.default_branch,
+            repo.upstream,
+            Some(repo.id),
+            repo.topics,
         )
     }
+
+    /// Send a GraphQL query to Github, returning the body of the response.
+    fn post_query<Q: Serialize>(
+        &self,
+        agent: &ureq::Agent,
+        token: &str,
+        query: &Q,
+    ) -> anyhow::Result<String> {
+        let res = agent
+            .post(&self.url)
+            .set("Authorization", format!("Bearer {}", token).as_str())
+            .send_json(json!(query));
+
+        let res = match res {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => match response.into_string() {
+                Ok(resp) => {
+                    bail!("Got status code {status}. Body: {resp}")
+                }
+                Err(e) => {
+                    bail!("Got status code {status}. Error reading body: {e}")
+                }
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(res.into_string()?)
+    }
+
+    /// Send a GraphQL query to Github, returning the data in the response.
+    fn send_query<Q: Serialize, R: DeserializeOwned>(
+        &self,
+        agent: &ureq::Agent,
+        token: &str,
+        query: &Q,
+    ) -> anyhow::Result<R> {
+        parse_response(&self.post_query(agent, token, query)?)
+    }
+
+    /// Build the query for a single page of repositories that matches our kind.
+    fn page_query(&self, after: Option<String>) -> anyhow::Result<serde_json::Value> {
+        let login = self.name.to_lowercase();
+
+        // include_forks needs to be None instead of true, as the graphql parameter has three
+        // states: false - no forks, true - only forks, none - all repositories.
+        let include_forks: Option<bool> = if self.skip_forks { Some(false) } else { None };
+
+        let query = match self.kind {
+            GithubSourceKind::Owner => json!(Repositories::build_query(repositories::Variables {
+                login,
+                include_forks,
+                after,
+            })),
+            GithubSourceKind::Starred => json!(StarredRepositories::build_query(
+                starred_repositories::Variables { login, after }
+            )),
+            GithubSourceKind::Watching => json!(WatchedRepositories::build_query(
+                watched_repositories::Variables { login, after }
+            )),
+            GithubSourceKind::Collaborator => json!(CollaboratorRepositories::build_query(
+                collaborator_repositories::Variables {
+                    login,
+                    include_forks,
+                    after,
+                }
+            )),
+            GithubSourceKind::Team => {
+                let team = self
+                    .team
+                    .clone()
+                    .with_context(|| "A team must be given when using the team kind")?;
+                json!(TeamRepositories::build_query(
+                    team_repositories::Variables { login, team, after }
+                ))
+            }
+        };
+        Ok(query)
+    }
+
+    /// Parse a single page of repositories from the response to `page_query`.
+    fn parse_page(&self, body: &str) -> anyhow::Result<RepositoryPage> {
+        let login = self.name.to_lowercase();
+        let missing = || format!("Github user/org {} could not be found", login);
+
+        let page = match self.kind {
+            GithubSourceKind::Owner => {
+                let data: repositories::ResponseData = parse_response(body)?;
+                let repos = data.repository_owner.with_context(missing)?.repositories;
+                RepositoryPage::new(
+                    repos.nodes,
+                    repos.page_info.has_next_page,
+                    repos.page_info.end_cursor,
+                )
+            }
+            GithubSourceKind::Starred => {
+                let data: starred_repositories::ResponseData = parse_response(body)?;
+                let repos = data.user.with_context(missing)?.starred_repositories;
+                RepositoryPage::new(
+                    repos.nodes,
+                    repos.page_info.has_next_page,
+                    repos.page_info.end_cursor,
+                )
+            }
+            GithubSourceKind::Watching => {
+                let data: watched_repositories::ResponseData = parse_response(body)?;
+                let repos = data.user.with_context(missing)?.watching;
+                RepositoryPage::new(
+                    repos.nodes,
+                    repos.page_info.has_next_page,
+                    repos.page_info.end_cursor,
+                )
+            }
+            GithubSourceKind::Collaborator => {
+                let data: collaborator_repositories::ResponseData = parse_response(body)?;
+                let repos = data.user.with_context(missing)?.repositories;
+                RepositoryPage::new(
+                    repos.nodes,
+                    repos.page_info.has_next_page,
+                    repos.page_info.end_cursor,
+                )
+            }
+            GithubSourceKind::Team => {
+                let team = self.team.as_deref().unwrap_or_default();
+                let data: team_repositories::ResponseData = parse_response(body)?;
+                let repos = data
+                    .organization
+                    .with_context(missing)?
+                    .team
+                    .with_context(|| format!("Team {} could not be found in {}", team, login))?
+                    .repositories;
+                RepositoryPage::new(
+                    repos.nodes,
+                    repos.page_info.has_next_page,
+                    repos.page_info.end_cursor,
+                )
+            }
+        };
+        Ok(page)
+    }
+
+    /// Fetch a single page of repositories, using the query that matches our kind.
+    fn fetch_page(
+        &self,
+        agent: &ureq::Agent,
+        token: &str,
+        after: Option<String>,
+    ) -> anyhow::Result<RepositoryPage> {
+        let body = self.post_query(agent, token, &self.page_query(after)?)?;
+        self.parse_page(&body)
+    }
+
+    /// Turn repository nodes into repositories, skipping archived, forked and excluded ones.
+    fn repositories(&self, nodes: Vec<RepositoryNode>) -> anyhow::Result<Vec<Repository>> {
+        let exclude_regex_set = create_exclude_regex_set(&self.exclude)?;
+        let template = parse_path_template(&self.path_template)?;
+
+        let repositories = nodes
+            .into_iter()
+            .filter(|r| !r.is_archived)
+            // Not every query can filter forks on the server, so do it here as well.
+            .filter(|r| !(self.skip_forks && r.is_fork))
+            .filter(|r| !exclude_regex_set.is_match(&r.name_with_owner))
+            .map(|repo| self.parse_repo(template.as_ref(), repo))
+            .collect();
+        Ok(repositories)
+    }
+}
+
+/// Parse the response to a GraphQL query, returning its data or the errors it contains.
+fn parse_response<R: DeserializeOwned>(body: &str) -> anyhow::Result<R> {
+    let response_data: Response<R> = serde_json::from_str(body)?;
+
+    if let Some(errors) = response_data.errors {
+        let total_errors = errors.len();
+        let combined_errors: Vec<_> = errors.into_iter().map(|e| e.message).collect();
+        let combined_message = combined_errors.join("\n");
+        bail!(
+            "Received {} errors. Errors:\n{}",
+            total_errors,
+            combined_message
+        );
+    }
+
+    response_data
+        .data
+        .with_context(|| format!("Invalid response from GitHub: {}", body))
 }
 
 impl Provider for GithubProvider {
@@ -136,89 +538,196 @@ impl Provider for GithubProvider {
             println!("You specified: {}", self.name);
             return false;
         }
+        if let Err(e) = self.validate() {
+            println!("{}", style(format!("Error: {}", e)).red());
+            return false;
+        }
         true
     }
 
+    fn validate(&self) -> anyhow::Result<()> {
+        create_exclude_regex_set(&self.exclude)?;
+        parse_path_template(&self.path_template)?;
+        if self.kind == GithubSourceKind::Team && self.team.is_none() {
+            bail!("A team must be given with --team when using the team kind");
+        }
+        Ok(())
+    }
+
     fn fetch_repositories(&self) -> anyhow::Result<Vec<Repository>> {
-        let github_token = env::var("GITHUB_TOKEN")
-            .with_context(|| "Missing GITHUB_TOKEN environment variable")?;
-        let mut repositories = vec![];
+        let github_token = env::var(&self.env_var)
+            .with_context(|| format!("Missing {} environment variable", self.env_var))?;
+        let mut nodes = vec![];
 
         let mut after = None;
 
-        let exclude_regex_set = create_exclude_regex_set(&self.exclude)?;
-
-        // include_forks needs to be None instead of true, as the graphql parameter has three
-        // states: false - no forks, true - only forks, none - all repositories.
-        let include_forks: Option<bool> = if self.skip_forks { Some(false) } else { None };
-
         let agent = ureq::AgentBuilder::new()
             .https_only(true)
             .user_agent(APP_USER_AGENT)
             .build();
 
         loop {
-            let q = Repositories::build_query(repositories::Variables {
-                login: self.name.to_lowercase(),
-                include_forks,
-                after,
-            });
-            let res = agent
-                .post(&self.url)
-                .set("Authorization", format!("Bearer {}", github_token).as_str())
-                .send_json(json!(&q));
-
-            let res = match res {
-                Ok(response) => response,
-                Err(ureq::Error::Status(status, response)) => match response.into_string() {
-                    Ok(resp) => {
-                        bail!("Got status code {status}. Body: {resp}")
-                    }
-                    Err(e) => {
-                        bail!("Got status code {status}. Error reading body: {e}")
-                    }
-                },
-                Err(e) => return Err(e.into()),
-            };
-
-            let body = res.into_string()?;
-            let response_data: Response<repositories::ResponseData> = serde_json::from_str(&body)?;
-
-            if let Some(errors) = response_data.errors {
-                let total_errors = errors.len();
-                let combined_errors: Vec<_> = errors.into_iter().map(|e| e.message).collect();
-                let combined_message = combined_errors.join("\n");
-                bail!(
-                    "Received {} errors. Errors:\n{}",
-                    total_errors,
-                    combined_message
-                );
-            }
-
-            let response_repositories = response_data
-                .data
-                .with_context(|| format!("Invalid response from GitHub: {}", body))?
-                .repository_owner
-                .with_context(|| format!("Invalid response from GitHub: {}", body))?
-                .repositories;
-
-            repositories.extend(
-                response_repositories
-                    .nodes
-                    .unwrap()
-                    .iter()
-                    .map(|r| r.as_ref().unwrap())
-                    .filter(|r| !r.is_archived)
-                    .filter(|r| !exclude_regex_set.is_match(&r.name_with_owner))
-                    .map(|repo| self.parse_repo(&self.path, repo)),
-            );
+            let page = self.fetch_page(&agent, &github_token, after)?;
+            nodes.extend(page.nodes);
 
-            if !response_repositories.page_info.has_next_page {
+            if !page.has_next_page {
                 break;
             }
-            after = response_repositories.page_info.end_cursor;
+            after = page.end_cursor;
         }
 
-        Ok(repositories)
+        self.repositories(nodes)
+    }
+
+    fn verify_token(&self) -> anyhow::Result<String> {
+        let github_token = env::var(&self.env_var)
+            .with_context(|| format!("Missing {} environment variable", self.env_var))?;
+        let agent = ureq::AgentBuilder::new()
+            .https_only(true)
+            .user_agent(APP_USER_AGENT)
+            .build();
+        let query = json!({ "query": "query { viewer { login } }" });
+        let data: ViewerData = self.send_query(&agent, &github_token, &query)?;
+        Ok(data.viewer.login)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(args: &[&str]) -> GithubProvider {
+        GithubProvider::from_iter_safe(["github", "octo-org"].iter().chain(args)).unwrap()
+    }
+
+    fn owner_nodes() -> Vec<RepositoryNode> {
+        let body = include_str!("fixtures/github/owner.json");
+        provider(&[]).parse_page(body).unwrap().nodes
+    }
+
+    fn names(repositories: &[Repository]) -> Vec<&str> {
+        repositories.iter().map(|r| r.name().as_str()).collect()
+    }
+
+    #[test]
+    fn parses_owner_repositories() {
+        let body = include_str!("fixtures/github/owner.json");
+        let page = provider(&[]).parse_page(body).unwrap();
+
+        assert!(page.has_next_page);
+        assert_eq!(page.end_cursor.as_deref(), Some("Y3Vyc29yOjEwMA=="));
+        assert_eq!(page.nodes.len(), 4);
+        assert_eq!(page.nodes[0].id, "R_1");
+        assert_eq!(page.nodes[0].topics, vec!["rust", "backend"]);
+        assert_eq!(page.nodes[0].default_branch.as_deref(), Some("main"));
+        assert!(page.nodes[1].is_archived);
+        assert!(page.nodes[2].is_fork);
+        assert_eq!(
+            page.nodes[2].upstream.as_deref(),
+            Some("git@github.com:torvalds/linux.git")
+        );
+        assert_eq!(page.nodes[3].default_branch, None);
+    }
+
+    #[test]
+    fn parses_every_kind() {
+        let cases = [
+            (
+                "starred",
+                include_str!("fixtures/github/starred.json"),
+                "rust-lang/rust",
+            ),
+            (
+                "watching",
+                include_str!("fixtures/github/watching.json"),
+                "octocat/hello-world",
+            ),
+            (
+                "collaborator",
+                include_str!("fixtures/github/collaborator.json"),
+                "someone/shared",
+            ),
+            (
+                "team",
+                include_str!("fixtures/github/team.json"),
+                "octo-org/infra",
+            ),
+        ];
+        for (kind, body, name) in cases {
+            let page = provider(&["--kind", kind, "--team", "platform"])
+                .parse_page(body)
+                .unwrap();
+            assert!(!page.has_next_page, "{}", kind);
+            assert_eq!(page.end_cursor, None, "{}", kind);
+            assert_eq!(page.nodes[0].name_with_owner, name, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn missing_team_is_an_error() {
+        let body = include_str!("fixtures/github/team_not_found.json");
+        let error = provider(&["--kind", "team", "--team", "platform"])
+            .parse_page(body)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Team platform could not be found in octo-org"
+        );
+    }
+
+    #[test]
+    fn missing_owner_is_an_error() {
+        let body = include_str!("fixtures/github/owner_not_found.json");
+        let error = provider(&[]).parse_page(body).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Github user/org octo-org could not be found"
+        );
+    }
+
+    #[test]
+    fn graphql_errors_are_reported() {
+        let body = include_str!("fixtures/github/user_not_found.json");
+        let error = provider(&["--kind", "starred"])
+            .parse_page(body)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Received 1 errors. Errors:\nCould not resolve to a User with the login of 'nobody'."
+        );
+    }
+
+    #[test]
+    fn repositories_skip_archived() {
+        let repositories = provider(&[]).repositories(owner_nodes()).unwrap();
+        assert_eq!(
+            names(&repositories),
+            [
+                "github/octo-org/api",
+                "github/octo-org/linux",
+                "github/octo-org/empty"
+            ]
+        );
+    }
+
+    #[test]
+    fn repositories_skip_forks() {
+        let repositories = provider(&["--skip-forks"])
+            .repositories(owner_nodes())
+            .unwrap();
+        assert_eq!(
+            names(&repositories),
+            ["github/octo-org/api", "github/octo-org/empty"]
+        );
+    }
+
+    #[test]
+    fn repositories_skip_excluded() {
+        let repositories = provider(&["--exclude", "^octo-org/(api|linux)$"])
+            .repositories(owner_nodes())
+            .unwrap();
+        assert_eq!(names(&repositories), ["github/octo-org/empty"]);
     }
 }
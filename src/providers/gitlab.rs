@@ -1,8 +1,12 @@
-use crate::providers::{create_exclude_regex_set, Provider, APP_USER_AGENT};
-use crate::repository::Repository;
+use crate::providers::{
+    create_exclude_regex_set, parse_path_template, PathTemplate, Provider, TemplateContext,
+    APP_USER_AGENT,
+};
+use crate::repository::{CloneOptions, Repository};
 use anyhow::{anyhow, Context};
 use console::style;
 use graphql_client::{GraphQLQuery, Response};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fmt;
@@ -22,18 +26,32 @@ use structopt::StructOpt;
 pub struct Repositories;
 
 struct ProjectNode {
+    id: String,
     archived: bool,
     full_path: String,
     ssh_url: String,
+    topics: Vec<String>,
     root_ref: Option<String>,
 }
 
+fn parse_tag_list(tag_list: Option<String>) -> Vec<String> {
+    tag_list
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 impl From<repositories::RepositoriesGroupProjectsEdgesNode> for ProjectNode {
     fn from(item: repositories::RepositoriesGroupProjectsEdgesNode) -> Self {
         Self {
+            id: item.id,
             archived: item.archived.unwrap(),
             root_ref: item.repository.and_then(|r| r.root_ref),
             ssh_url: item.ssh_url_to_repo.expect("Unknown SSH URL"),
+            topics: parse_tag_list(item.tag_list),
             full_path: item.full_path,
         }
     }
@@ -42,15 +60,109 @@ impl From<repositories::RepositoriesGroupProjectsEdgesNode> for ProjectNode {
 impl From<repositories::RepositoriesNamespaceProjectsEdgesNode> for ProjectNode {
     fn from(item: repositories::RepositoriesNamespaceProjectsEdgesNode) -> Self {
         Self {
+            id: item.id,
             archived: item.archived.unwrap(),
             root_ref: item.repository.and_then(|r| r.root_ref),
             ssh_url: item.ssh_url_to_repo.expect("Unknown SSH URL"),
+            topics: parse_tag_list(item.tag_list),
             full_path: item.full_path,
         }
     }
 }
 
-static DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
+/// The user that owns a token, from the REST API.
+#[derive(Deserialize, Debug)]
+struct CurrentUser {
+    username: String,
+}
+
+// Projects shared with a group are not exposed through the GraphQL API, so we fetch them
+// from the REST API instead.
+#[derive(Deserialize, Debug)]
+struct SharedProject {
+    id: u64,
+    archived: bool,
+    path_with_namespace: String,
+    ssh_url_to_repo: String,
+    #[serde(default)]
+    topics: Vec<String>,
+    default_branch: Option<String>,
+}
+
+impl From<SharedProject> for ProjectNode {
+    fn from(item: SharedProject) -> Self {
+        Self {
+            // Match the global ID format used by the GraphQL API
+            id: format!("gid://gitlab/Project/{}", item.id),
+            archived: item.archived,
+            root_ref: item.default_branch,
+            ssh_url: item.ssh_url_to_repo,
+            topics: item.topics,
+            full_path: item.path_with_namespace,
+        }
+    }
+}
+
+/// The REST API returns the group's own projects as well as the shared ones, which we already
+/// have from the GraphQL API.
+fn filter_shared_projects(shared: Vec<SharedProject>, name: &str) -> Vec<ProjectNode> {
+    let own_prefix = format!("{}/", name);
+    shared
+        .into_iter()
+        .filter(|p| {
+            !p.path_with_namespace
+                .to_lowercase()
+                .starts_with(&own_prefix)
+        })
+        .map(ProjectNode::from)
+        .collect()
+}
+
+/// Parse a page of the GraphQL projects query, returning the projects and the cursor of the
+/// next page.
+fn parse_projects_page(
+    json: serde_json::Value,
+    name: &str,
+) -> anyhow::Result<(Vec<ProjectNode>, Option<String>)> {
+    let response_body: Response<repositories::ResponseData> = serde_json::from_value(json)?;
+    let data = response_body.data.expect("Missing data");
+
+    // This is annoying but I'm still not sure how to unify it.
+    if let Some(group) = data.group {
+        let group_data = group.projects;
+        let projects = group_data
+            .edges
+            .expect("missing edges")
+            .into_iter()
+            // Some(T) -> T
+            .flatten()
+            // Extract the node, which is also Some(T)
+            .filter_map(|x| x.node)
+            .map(ProjectNode::from)
+            .collect();
+        Ok((projects, group_data.page_info.end_cursor))
+    } else if let Some(namespace) = data.namespace {
+        let namespace_data = namespace.projects;
+        let projects = namespace_data
+            .edges
+            .expect("missing edges")
+            .into_iter()
+            // Some(T) -> T
+            .flatten()
+            // Extract the node, which is also Some(T)
+            .filter_map(|x| x.node)
+            .map(ProjectNode::from)
+            .collect();
+        Ok((projects, namespace_data.page_info.end_cursor))
+    } else {
+        Err(anyhow!(
+            "Gitlab group/user {} could not be found. Are you sure you have access?",
+            name
+        ))
+    }
+}
+
+pub static DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
 
 fn public_gitlab_url() -> String {
     DEFAULT_GITLAB_URL.to_string()
@@ -60,7 +172,7 @@ fn default_env_var() -> String {
     String::from("GITHUB_TOKEN")
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, Ord, PartialEq, PartialOrd, StructOpt)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Eq, Ord, PartialEq, PartialOrd, StructOpt)]
 #[serde(rename_all = "lowercase")]
 #[structopt(about = "Add a Gitlab user or group by name")]
 pub struct GitlabProvider {
@@ -73,6 +185,12 @@ pub struct GitlabProvider {
     #[structopt(long = "path", default_value = "gitlab")]
     /// Clone repos to a specific path
     path: String,
+    #[structopt(long = "path-template")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Clone repos to a path built from a template, like "{host}/{group_path}/{name}".
+    /// Supports {path}, {host}, {owner}, {name}, {group_path} and {topic}, optionally with
+    /// a case transform like {owner:lower} or {name:upper}.
+    path_template: Option<String>,
     #[structopt(long = "env-name", short = "e", default_value = "GITLAB_TOKEN")]
     #[serde(default = "default_env_var")]
     /// Environment variable containing the auth token
@@ -83,6 +201,32 @@ pub struct GitlabProvider {
     /// Don't clone repositories that match these regular expressions. The repository name
     /// includes the user or organisation name.
     exclude: Vec<String>,
+
+    #[structopt(long = "include-shared")]
+    #[serde(default)]
+    /// Also clone projects from other groups that have been shared with this group
+    include_shared: bool,
+
+    #[structopt(long = "skip-subgroups")]
+    #[serde(default)]
+    /// Only clone projects that are directly inside the group, ignoring all subgroups
+    skip_subgroups: bool,
+
+    #[structopt(long = "exclude-subgroup")]
+    #[serde(default)]
+    /// Don't clone projects inside subgroups that match these regular expressions. The subgroup
+    /// name includes the full path of the group, e.g. "my-group/legacy".
+    exclude_subgroups: Vec<String>,
+
+    #[structopt(long = "flatten-subgroups")]
+    #[serde(default)]
+    /// Clone all projects directly into the group directory rather than preserving the
+    /// subgroup hierarchy
+    flatten_subgroups: bool,
+
+    #[structopt(flatten)]
+    #[serde(flatten)]
+    pub clone_options: CloneOptions,
     // Currently does not work.
     // https://gitlab.com/gitlab-org/gitlab/issues/121595
     //    #[structopt(long = "skip-forks")]
@@ -104,6 +248,103 @@ impl fmt::Display for GitlabProvider {
     }
 }
 
+impl GitlabProvider {
+    /// Work out where a project should be cloned to, relative to the workspace.
+    fn project_path(
+        &self,
+        template: Option<&PathTemplate>,
+        name: &str,
+        project: &ProjectNode,
+    ) -> String {
+        let full_path = project.full_path.as_str();
+        if let Some(template) = template {
+            template.render(&TemplateContext {
+                path: &self.path,
+                url: &project.ssh_url,
+                full_path,
+                topics: &project.topics,
+            })
+        } else if self.flatten_subgroups {
+            let project_name = full_path.rsplit('/').next().unwrap_or(full_path);
+            format!("{}/{}/{}", self.path, name, project_name)
+        } else {
+            format!("{}/{}", self.path, full_path)
+        }
+    }
+
+    /// Turn the projects of the group `name` into repositories, skipping archived and
+    /// excluded projects.
+    fn repositories(
+        &self,
+        projects: Vec<ProjectNode>,
+        name: &str,
+    ) -> anyhow::Result<Vec<Repository>> {
+        let exclude_regex_set = create_exclude_regex_set(&self.exclude)?;
+        let exclude_subgroups_regex_set = create_exclude_regex_set(&self.exclude_subgroups)?;
+        let template = parse_path_template(&self.path_template)?;
+
+        let repositories = projects
+            .into_iter()
+            .filter(|r| !r.archived)
+            .filter(|r| !exclude_regex_set.is_match(&r.full_path))
+            .filter(|r| match r.full_path.rsplit_once('/') {
+                Some((namespace, _)) => !exclude_subgroups_regex_set.is_match(namespace),
+                None => true,
+            })
+            .map(|r| {
+                Repository::new(
+                    self.project_path(template.as_ref(), name, &r),
+                    r.ssh_url,
+                    r.root_ref,
+                    None,
+                    Some(r.id),
+                    r.topics,
+                )
+            })
+            .collect();
+        Ok(repositories)
+    }
+
+    /// Fetch all projects that have been shared with the group `name` from other groups.
+    fn fetch_shared_projects(
+        &self,
+        agent: &ureq::Agent,
+        token: &str,
+        name: &str,
+    ) -> anyhow::Result<Vec<ProjectNode>> {
+        let mut projects = vec![];
+        let mut page = "1".to_string();
+
+        loop {
+            let res = agent
+                .get(
+                    format!(
+                        "{}/api/v4/groups/{}/projects",
+                        self.url,
+                        name.replace('/', "%2F")
+                    )
+                    .as_str(),
+                )
+                .set("Authorization", format!("Bearer {}", token).as_str())
+                .query("with_shared", "true")
+                .query("include_subgroups", &(!self.skip_subgroups).to_string())
+                .query("per_page", "100")
+                .query("page", &page)
+                .call()
+                .with_context(|| format!("Error fetching projects shared with {}", name))?;
+            let next_page = res.header("X-Next-Page").unwrap_or_default().to_string();
+            let shared: Vec<SharedProject> = res.into_json()?;
+            projects.extend(filter_shared_projects(shared, name));
+
+            if next_page.is_empty() {
+                break;
+            }
+            page = next_page;
+        }
+        Ok(projects)
+    }
+}
+
 impl Provider for GitlabProvider {
     fn correctly_configured(&self) -> bool {
         let token = env::var(&self.env_var);
@@ -132,17 +373,27 @@ impl Provider for GitlabProvider {
             println!("You specified: {}", self.name);
             return false;
         }
+        if let Err(e) = self.validate() {
+            println!("{}", style(format!("Error: {}", e)).red());
+            return false;
+        }
         true
     }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        create_exclude_regex_set(&self.exclude)?;
+        create_exclude_regex_set(&self.exclude_subgroups)
+            .context("Error parsing exclude_subgroups regular expressions")?;
+        parse_path_template(&self.path_template)?;
+        Ok(())
+    }
     fn fetch_repositories(&self) -> anyhow::Result<Vec<Repository>> {
         let gitlab_token = env::var(&self.env_var)
             .with_context(|| format!("Missing {} environment variable", self.env_var))?;
-        let mut repositories = vec![];
+        let mut projects = vec![];
         let mut after = Some("".to_string());
         let name = self.name.to_string().to_lowercase();
 
-        let exclude_regex_set = create_exclude_regex_set(&self.exclude)?;
-
         let agent = ureq::AgentBuilder::new()
             .https_only(true)
             .user_agent(APP_USER_AGENT)
@@ -152,71 +403,149 @@ impl Provider for GitlabProvider {
             let q = Repositories::build_query(repositories::Variables {
                 name: name.clone(),
                 after,
+                include_subgroups: Some(!self.skip_subgroups),
             });
             let res = agent
                 .post(format!("{}/api/graphql", self.url).as_str())
                 .set("Authorization", format!("Bearer {}", gitlab_token).as_str())
                 .set("Content-Type", "application/json")
                 .send_json(json!(&q))?;
-            let json = res.into_json()?;
-
-            let response_body: Response<repositories::ResponseData> = serde_json::from_value(json)?;
-            let data = response_body.data.expect("Missing data");
-
-            let temp_repositories: Vec<ProjectNode>;
-            // This is annoying but I'm still not sure how to unify it.
-            if data.group.is_some() {
-                let group_data = data.group.expect("Missing group").projects;
-                temp_repositories = group_data
-                    .edges
-                    .expect("missing edges")
-                    .into_iter()
-                    // Some(T) -> T
-                    .flatten()
-                    // Extract the node, which is also Some(T)
-                    .filter_map(|x| x.node)
-                    .map(ProjectNode::from)
-                    .collect();
-                after = group_data.page_info.end_cursor;
-            } else if data.namespace.is_some() {
-                let namespace_data = data.namespace.expect("Missing namespace").projects;
-                temp_repositories = namespace_data
-                    .edges
-                    .expect("missing edges")
-                    .into_iter()
-                    // Some(T) -> T
-                    .flatten()
-                    // Extract the node, which is also Some(T)
-                    .filter_map(|x| x.node)
-                    .map(ProjectNode::from)
-                    .collect();
-                after = namespace_data.page_info.end_cursor;
-            } else {
-                return Err(anyhow!(
-                    "Gitlab group/user {} could not be found. Are you sure you have access?",
-                    name
-                ));
-            }
-
-            repositories.extend(
-                temp_repositories
-                    .into_iter()
-                    .filter(|r| !r.archived)
-                    .filter(|r| !exclude_regex_set.is_match(&r.full_path))
-                    .map(|r| {
-                        Repository::new(
-                            format!("{}/{}", self.path, r.full_path),
-                            r.ssh_url,
-                            r.root_ref,
-                            None,
-                        )
-                    }),
-            );
+            let (page, end_cursor) = parse_projects_page(res.into_json()?, &name)?;
+            after = end_cursor;
+            projects.extend(page);
 
             if after.is_none() {
                 break;
             }
         }
-        Ok(repositories)
+
+        if self.include_shared {
+            projects.extend(self.fetch_shared_projects(&agent, &gitlab_token, &name)?);
+        }
+
+        self.repositories(projects, &name)
+    }
+
+    fn verify_token(&self) -> anyhow::Result<String> {
+        let gitlab_token = env::var(&self.env_var)
+            .with_context(|| format!("Missing {} environment variable", self.env_var))?;
+        let agent = ureq::AgentBuilder::new()
+            .https_only(true)
+            .user_agent(APP_USER_AGENT)
+            .build();
+        let user: CurrentUser = agent
+            .get(format!("{}/api/v4/user", self.url).as_str())
+            .set("Authorization", format!("Bearer {}", gitlab_token).as_str())
+            .call()
+            .with_context(|| format!("Error fetching the user from {}", self.url))?
+            .into_json()?;
+        Ok(user.username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(args: &[&str]) -> GitlabProvider {
+        GitlabProvider::from_iter_safe(["gitlab", "my-group"].iter().chain(args)).unwrap()
+    }
+
+    fn group_projects() -> Vec<ProjectNode> {
+        let json = serde_json::from_str(include_str!("fixtures/gitlab/group_projects.json"));
+        parse_projects_page(json.unwrap(), "my-group").unwrap().0
+    }
+
+    fn names(repositories: &[Repository]) -> Vec<&str> {
+        repositories.iter().map(|r| r.name().as_str()).collect()
+    }
+
+    #[test]
+    fn parses_group_projects() {
+        let json = serde_json::from_str(include_str!("fixtures/gitlab/group_projects.json"));
+        let (projects, after) = parse_projects_page(json.unwrap(), "my-group").unwrap();
+
+        assert_eq!(after, None);
+        assert_eq!(projects.len(), 4);
+        assert_eq!(projects[0].id, "gid://gitlab/Project/1");
+        assert_eq!(projects[0].topics, vec!["rust", "backend"]);
+        assert_eq!(projects[0].root_ref.as_deref(), Some("main"));
+        assert!(projects[1].topics.is_empty());
+        assert_eq!(projects[2].root_ref, None);
+        assert!(projects[3].archived);
+    }
+
+    #[test]
+    fn parses_namespace_projects_and_cursor() {
+        let json = serde_json::from_str(include_str!("fixtures/gitlab/namespace_projects.json"));
+        let (projects, after) = parse_projects_page(json.unwrap(), "someone").unwrap();
+
+        assert_eq!(after.as_deref(), Some("eyJpZCI6IjEwIn0"));
+        assert_eq!(projects.len(), 1);
+        assert_eq!(projects[0].full_path, "someone/dotfiles");
+    }
+
+    #[test]
+    fn missing_group_is_an_error() {
+        let json = serde_json::from_str(include_str!("fixtures/gitlab/not_found.json"));
+        let error = parse_projects_page(json.unwrap(), "nobody").err().unwrap();
+        assert!(error.to_string().contains("nobody could not be found"));
+    }
+
+    #[test]
+    fn shared_projects_skip_the_groups_own_projects() {
+        let shared: Vec<SharedProject> =
+            serde_json::from_str(include_str!("fixtures/gitlab/shared_projects.json")).unwrap();
+        let projects = filter_shared_projects(shared, "my-group");
+
+        let paths: Vec<&str> = projects.iter().map(|p| p.full_path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["other-group/shared-lib", "my-group-extra/lookalike"]
+        );
+        assert_eq!(projects[0].id, "gid://gitlab/Project/20");
+        assert_eq!(projects[0].root_ref.as_deref(), Some("develop"));
+        assert!(projects[0].topics.is_empty());
+    }
+
+    #[test]
+    fn repositories_keep_the_subgroup_hierarchy() {
+        let repositories = provider(&[])
+            .repositories(group_projects(), "my-group")
+            .unwrap();
+        assert_eq!(
+            names(&repositories),
+            [
+                "gitlab/my-group/api",
+                "gitlab/my-group/tools/cli",
+                "gitlab/my-group/legacy/old-api",
+            ]
+        );
+    }
+
+    #[test]
+    fn repositories_skip_excluded_subgroups() {
+        let repositories = provider(&["--exclude-subgroup", "^my-group/legacy$"])
+            .repositories(group_projects(), "my-group")
+            .unwrap();
+        assert_eq!(
+            names(&repositories),
+            ["gitlab/my-group/api", "gitlab/my-group/tools/cli"]
+        );
+    }
+
+    #[test]
+    fn repositories_can_be_flattened() {
+        let repositories = provider(&["--flatten-subgroups"])
+            .repositories(group_projects(), "my-group")
+            .unwrap();
+        assert_eq!(
+            names(&repositories),
+            [
+                "gitlab/my-group/api",
+                "gitlab/my-group/cli",
+                "gitlab/my-group/old-api",
+            ]
+        );
     }
 }
@@ -1,18 +1,26 @@
 mod github;
 mod gitlab;
+mod template;
 
 use crate::repository::Repository;
 use anyhow::Context;
-pub use github::GithubProvider;
-pub use gitlab::GitlabProvider;
+pub use github::{GithubProvider, DEFAULT_GITHUB_URL};
+pub use gitlab::{GitlabProvider, DEFAULT_GITLAB_URL};
 use std::fmt;
+pub use template::{host_from_url, PathTemplate, TemplateContext};
 
 pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
 pub trait Provider: fmt::Display {
     /// Returns true if the provider should work, otherwise prints an error and return false
     fn correctly_configured(&self) -> bool;
+    /// Returns an error if any of the provider's options are invalid, like a regular expression
+    /// that does not compile
+    fn validate(&self) -> anyhow::Result<()>;
     fn fetch_repositories(&self) -> anyhow::Result<Vec<Repository>>;
+    /// Make a test request with the provider's token, returning the name of the user it
+    /// belongs to
+    fn verify_token(&self) -> anyhow::Result<String>;
 }
 
 pub fn create_exclude_regex_set(items: &Vec<String>) -> anyhow::Result<regex::RegexSet> {
@@ -22,3 +30,7 @@ pub fn create_exclude_regex_set(items: &Vec<String>) -> anyhow::Result<regex::Re
         Ok(regex::RegexSet::new(items).context("Error parsing exclude regular expressions")?)
     }
 }
+
+pub fn parse_path_template(template: &Option<String>) -> anyhow::Result<Option<PathTemplate>> {
+    template.as_deref().map(PathTemplate::parse).transpose()
+}
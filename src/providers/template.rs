@@ -0,0 +1,235 @@
+use anyhow::{anyhow, bail};
+
+static PLACEHOLDERS: &[&str] = &["path", "host", "owner", "name", "group_path", "topic"];
+
+/// How the value of a placeholder should be transformed before it is inserted.
+#[derive(Debug, Clone, Copy)]
+enum Case {
+    Unchanged,
+    Lower,
+    Upper,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder { name: String, case: Case },
+}
+
+/// A template describing where a repository should be placed within the workspace,
+/// for example `{host}/{owner}/{name}`. Placeholders can be given a case transform by
+/// appending `:lower` or `:upper`, like `{owner:lower}`.
+#[derive(Debug)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+/// The values that can be substituted into a `PathTemplate` for a single repository.
+pub struct TemplateContext<'a> {
+    /// The base path configured on the provider
+    pub path: &'a str,
+    /// The URL the repository will be cloned from
+    pub url: &'a str,
+    /// The full path of the repository on the provider, like `owner/group/name`
+    pub full_path: &'a str,
+    pub topics: &'a [String],
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> anyhow::Result<PathTemplate> {
+        let mut segments = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if rest[..start].contains('}') {
+                bail!("Unopened placeholder in path template {}", template);
+            }
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in path template {}", template))?;
+            let placeholder = &rest[start + 1..start + end];
+            let (name, case) = match placeholder.split_once(':') {
+                None => (placeholder, Case::Unchanged),
+                Some((name, "lower")) => (name, Case::Lower),
+                Some((name, "upper")) => (name, Case::Upper),
+                Some((_, case)) => bail!(
+                    "Unknown case transform {} in path template {}, expected lower or upper",
+                    case,
+                    template
+                ),
+            };
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "Unknown placeholder {{{}}} in path template {}, expected one of {}",
+                    name,
+                    template,
+                    PLACEHOLDERS.join(", ")
+                );
+            }
+            segments.push(Segment::Placeholder {
+                name: name.to_string(),
+                case,
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            bail!("Unopened placeholder in path template {}", template);
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(PathTemplate { segments })
+    }
+
+    pub fn render(&self, context: &TemplateContext) -> String {
+        let (group_path, name) = context
+            .full_path
+            .rsplit_once('/')
+            .unwrap_or(("", context.full_path));
+        let owner = group_path.split('/').next().unwrap_or_default();
+
+        let rendered: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Placeholder {
+                    name: placeholder,
+                    case,
+                } => {
+                    let value = match placeholder.as_str() {
+                        "path" => context.path,
+                        "host" => host_from_url(context.url),
+                        "owner" => owner,
+                        "name" => name,
+                        "group_path" => group_path,
+                        "topic" => context.topics.first().map_or("", String::as_str),
+                        _ => unreachable!("placeholders are validated when parsing"),
+                    };
+                    match case {
+                        Case::Unchanged => value.to_string(),
+                        Case::Lower => value.to_lowercase(),
+                        Case::Upper => value.to_uppercase(),
+                    }
+                }
+            })
+            .collect();
+
+        // Placeholders can be empty (a repository without any topics, for example), so remove
+        // any empty directories this leaves behind.
+        rendered
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Extract the host from a clone URL. This handles both URLs with a scheme, like
+/// `ssh://git@host:22/owner/name.git`, and scp-like URLs like `git@host:owner/name.git`.
+pub fn host_from_url(url: &str) -> &str {
+    let without_scheme = match url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => url,
+    };
+    let authority = without_scheme.split('/').next().unwrap_or(without_scheme);
+    let without_user = match authority.split_once('@') {
+        Some((_, rest)) => rest,
+        None => authority,
+    };
+    without_user.split(':').next().unwrap_or(without_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, url: &str, full_path: &str, topics: &[String]) -> String {
+        PathTemplate::parse(template)
+            .unwrap()
+            .render(&TemplateContext {
+                path: "gitlab",
+                url,
+                full_path,
+                topics,
+            })
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let topics = vec!["Backend".to_string(), "rust".to_string()];
+        assert_eq!(
+            render(
+                "{path}/{host}/{group_path}/{name}",
+                "git@gitlab.com:my-group/tools/cli.git",
+                "my-group/tools/cli",
+                &topics
+            ),
+            "gitlab/gitlab.com/my-group/tools/cli"
+        );
+        assert_eq!(
+            render(
+                "{owner:upper}/{topic:lower}/{name}",
+                "git@gitlab.com:My-Group/tools/cli.git",
+                "My-Group/tools/cli",
+                &topics
+            ),
+            "MY-GROUP/backend/cli"
+        );
+    }
+
+    #[test]
+    fn empty_placeholders_leave_no_empty_directories() {
+        assert_eq!(
+            render(
+                "{topic}/{group_path}/{name}",
+                "git@host:cli.git",
+                "cli",
+                &[]
+            ),
+            "cli"
+        );
+        assert_eq!(
+            render(
+                "x/{topic}//{name}",
+                "git@host:owner/cli.git",
+                "owner/cli",
+                &[]
+            ),
+            "x/cli"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let error = |template| PathTemplate::parse(template).unwrap_err().to_string();
+        assert!(error("{owner}/{name").starts_with("Unclosed placeholder"));
+        assert!(error("{owner}/name}").starts_with("Unopened placeholder"));
+        assert!(error("a}/{name}").starts_with("Unopened placeholder"));
+        assert!(error("{owner}}/{name}").starts_with("Unopened placeholder"));
+        assert!(error("{owner:title}").starts_with("Unknown case transform title"));
+        assert!(error("{repo}").starts_with("Unknown placeholder {repo}"));
+        assert!(error("{}").starts_with("Unknown placeholder {}"));
+    }
+
+    #[test]
+    fn finds_the_host_of_clone_urls() {
+        assert_eq!(host_from_url("git@github.com:owner/name.git"), "github.com");
+        assert_eq!(
+            host_from_url("ssh://git@host.com:22/owner/name.git"),
+            "host.com"
+        );
+        assert_eq!(host_from_url("ssh://host.com/owner/name.git"), "host.com");
+        assert_eq!(
+            host_from_url("https://gitlab.com/owner/name.git"),
+            "gitlab.com"
+        );
+        assert_eq!(
+            host_from_url("https://user@host.com:8443/name.git"),
+            "host.com"
+        );
+    }
+}
@@ -1,14 +1,359 @@
 use anyhow::{anyhow, Context};
 use console::{strip_ansi_codes, truncate_str};
 use git2::build::CheckoutBuilder;
-use git2::{Repository as Git2Repository, StatusOptions};
+use git2::{BranchType, ConfigLevel, Repository as Git2Repository, Status, StatusOptions};
 use indicatif::ProgressBar;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Output, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(feature = "native-fetch")]
+use std::time::Instant;
+use structopt::StructOpt;
 extern crate dunce;
 
+// We have to normalize repository names. On windows if you do `path.join(self.name())`
+// it will cause issues if the name contains a forward slash. So here we just normalize it
+// to the path separator on the system.
+fn normalize_path(path: String) -> String {
+    if cfg!(windows) {
+        path.replace('/', std::path::MAIN_SEPARATOR.to_string().as_str())
+    } else {
+        path
+    }
+}
+
+/// Messages from git that mean a command failed because of a network problem, which may go away
+/// if the command is run again.
+static TRANSIENT_ERRORS: &[&str] = &[
+    "Could not resolve host",
+    "Temporary failure in name resolution",
+    "Connection timed out",
+    "Connection reset",
+    "Connection refused",
+    "Operation timed out",
+    "The remote end hung up unexpectedly",
+    "early EOF",
+    "RPC failed",
+    "unexpected disconnect",
+    "returned error: 502",
+    "returned error: 503",
+    "returned error: 504",
+];
+
+/// A command that failed in a way callers treat differently from other errors.
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command was killed because it ran for longer than the timeout
+    TimedOut(Duration),
+    /// The command failed with what looks like a network problem, so running it again may work
+    Transient(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::TimedOut(timeout) => {
+                write!(f, "Timed out after {} seconds", timeout.as_secs())
+            }
+            CommandError::Transient(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Run the command in its own process group when it has a timeout, so that `Watchdog` can
+/// kill any processes it starts too, like the `git-remote-https` helper that does the actual
+/// network requests.
+fn isolate_for_timeout(command: &mut Command, timeout: Option<Duration>) {
+    #[cfg(unix)]
+    if timeout.is_some() {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = (command, timeout);
+}
+
+/// Kills a command, and every process it started, if it runs for longer than a timeout.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Watchdog {
+    fn start(child: &Child, timeout: Option<Duration>) -> Watchdog {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let pid = child.id();
+        let thread = timeout.map(|timeout| {
+            thread::spawn(move || {
+                if stopped.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout) {
+                    return false;
+                }
+                kill_process_tree(pid);
+                true
+            })
+        });
+        Watchdog { stop, thread }
+    }
+
+    /// Stop watching the command, returning whether it was killed.
+    fn stop(self) -> bool {
+        let _ = self.stop.send(());
+        self.thread
+            .map(|thread| thread.join().unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_tree(pid: u32) {
+    // The command leads its own process group, so a negative pid signals the whole group.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_tree(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// Fetch every remote of `repo` with libgit2, showing progress as `name` in the progress bar.
+#[cfg(feature = "native-fetch")]
+fn fetch_remotes(
+    repo: &Git2Repository,
+    name: &str,
+    progress_bar: &ProgressBar,
+    timeout: Option<Duration>,
+    started: Instant,
+) -> anyhow::Result<()> {
+    use git2::{
+        AutotagOption, CertificateCheckStatus, Cred, CredentialType, ErrorClass, FetchOptions,
+        FetchPrune, RemoteCallbacks,
+    };
+    use indicatif::HumanBytes;
+
+    let config = repo.config()?;
+    // libgit2 can't be killed like a process, so it is cancelled from its callbacks. These are
+    // only called while connecting and while data arrives, so a connection that stalls without
+    // sending anything is only cancelled once it sends something or the OS gives up on it.
+    let expired = || timeout.is_some_and(|timeout| started.elapsed() > timeout);
+    let timed_out = || git2::Error::from_str("Timed out");
+
+    for remote_name in repo.remotes()?.iter().flatten() {
+        let mut remote = repo.find_remote(remote_name)?;
+        let mut callbacks = RemoteCallbacks::new();
+        // libgit2 asks again if the credentials are rejected, and the answer won't change
+        let mut asked_for_credentials = false;
+        callbacks.credentials(|url, username, allowed| {
+            if expired() {
+                return Err(timed_out());
+            }
+            if asked_for_credentials {
+                return Err(git2::Error::from_str("Authentication failed"));
+            }
+            asked_for_credentials = true;
+            if allowed.contains(CredentialType::SSH_KEY) {
+                Cred::ssh_key_from_agent(username.unwrap_or("git"))
+            } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                Cred::credential_helper(&config, url, username)
+            } else {
+                Cred::default()
+            }
+        });
+        callbacks.transfer_progress(|stats| {
+            let message = if stats.received_objects() < stats.total_objects() {
+                format!(
+                    "{}: {}: receiving objects {}/{} ({})",
+                    name,
+                    remote_name,
+                    stats.received_objects(),
+                    stats.total_objects(),
+                    HumanBytes(stats.received_bytes() as u64)
+                )
+            } else {
+                format!(
+                    "{}: {}: resolving deltas {}/{}",
+                    name,
+                    remote_name,
+                    stats.indexed_deltas(),
+                    stats.total_deltas()
+                )
+            };
+            progress_bar.set_message(message);
+            !expired()
+        });
+        callbacks.sideband_progress(|_| !expired());
+        // Passing through keeps libgit2's own certificate validation
+        callbacks.certificate_check(|_, _| {
+            if expired() {
+                return Err(timed_out());
+            }
+            Ok(CertificateCheckStatus::CertificatePassthrough)
+        });
+
+        let mut options = FetchOptions::new();
+        options
+            .remote_callbacks(callbacks)
+            .prune(FetchPrune::On)
+            .download_tags(AutotagOption::Auto);
+        progress_bar.set_message(format!("{}: fetching {}", name, remote_name));
+        // No refspecs means the ones configured for the remote, like `git fetch` does
+        if let Err(e) = remote.fetch::<&str>(&[], Some(&mut options), None) {
+            if expired() {
+                return Err(CommandError::TimedOut(timeout.unwrap_or_default()).into());
+            }
+            let message = format!("Error fetching {}: {}", remote_name, e.message());
+            if matches!(
+                e.class(),
+                ErrorClass::Net | ErrorClass::Os | ErrorClass::Http
+            ) {
+                return Err(CommandError::Transient(message).into());
+            }
+            return Err(anyhow!(message));
+        }
+    }
+    Ok(())
+}
+
+/// A partial clone filter, which leaves out objects that are then downloaded when they are
+/// first needed.
+#[derive(
+    Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd,
+)]
+pub enum CloneFilter {
+    /// Leave out the contents of files
+    #[serde(rename = "blob:none")]
+    BlobNone,
+    /// Leave out the contents of files and directories
+    #[serde(rename = "tree:0")]
+    TreeZero,
+}
+
+impl CloneFilter {
+    const VARIANTS: &'static [&'static str] = &["blob:none", "tree:0"];
+}
+
+impl FromStr for CloneFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blob:none" => Ok(Self::BlobNone),
+            "tree:0" => Ok(Self::TreeZero),
+            _ => Err(anyhow!(
+                "Unknown filter {}, expected one of {}",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CloneFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::BlobNone => "blob:none",
+            Self::TreeZero => "tree:0",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How a provider clones its repositories, to avoid downloading their whole history. These can
+/// be changed for some repositories with a `[[override]]`.
+#[derive(
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    StructOpt,
+    Debug,
+    Default,
+    Clone,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+)]
+pub struct CloneOptions {
+    #[structopt(long = "depth")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Create shallow clones with this many commits
+    depth: Option<u32>,
+    #[structopt(long = "filter", possible_values = CloneFilter::VARIANTS)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Create partial clones: "blob:none" downloads the contents of files when they are
+    /// needed, and "tree:0" does the same for directories
+    filter: Option<CloneFilter>,
+    #[structopt(long = "single-branch")]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    /// Only fetch the branch that is checked out
+    single_branch: bool,
+}
+
+impl CloneOptions {
+    pub fn apply(&self, repository: &mut Repository) {
+        if let Some(depth) = self.depth {
+            repository.depth = Some(depth);
+        }
+        if let Some(filter) = self.filter {
+            repository.filter = Some(filter);
+        }
+        if self.single_branch {
+            repository.single_branch = true;
+        }
+    }
+}
+
+/// The refspec `git clone` configures to fetch every branch of `origin`.
+static ALL_BRANCHES_REFSPEC: &str = "+refs/heads/*:refs/remotes/origin/*";
+
+/// The state of a checkout, as shown by `git workspace status`.
+#[derive(Serialize, Debug, Default)]
+pub struct RepositoryStatus {
+    /// The branch that is checked out, if HEAD is not detached
+    pub branch: Option<String>,
+    pub detached: bool,
+    /// Files with changes that have not been committed
+    pub changed: usize,
+    pub untracked: usize,
+    /// Commits that are not on the tracking branch
+    pub ahead: usize,
+    /// Commits on the tracking branch that we don't have
+    pub behind: usize,
+    pub stashes: usize,
+}
+
+impl RepositoryStatus {
+    /// Whether there is any work that only exists in this checkout.
+    pub fn is_dirty(&self) -> bool {
+        self.changed > 0 || self.untracked > 0 || self.ahead > 0 || self.stashes > 0
+    }
+
+    /// Whether the checkout is on `primary`, the branch it is expected to be on. Any branch
+    /// counts if we don't know the primary branch.
+    pub fn on_branch(&self, primary: Option<&str>) -> bool {
+        match (&self.branch, primary) {
+            _ if self.detached => false,
+            (Some(current), Some(primary)) => current == primary,
+            _ => true,
+        }
+    }
+}
+
 // Eq, Ord and friends are needed to order the list of repositories
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Repository {
@@ -16,6 +361,31 @@ pub struct Repository {
     url: String,
     pub upstream: Option<String>,
     pub branch: Option<String>,
+    /// A stable identifier for the repository on the provider, which stays the same if the
+    /// repository is renamed or transferred.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Create a shallow clone with this many commits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    /// Create a partial clone, which downloads objects when they are needed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<CloneFilter>,
+    /// Only fetch the branch that is checked out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub single_branch: bool,
+    /// Skip the repository when running `git workspace fetch`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_fetch: bool,
+    /// Only check out these directories, with a cone mode sparse checkout
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<String>,
+    /// The provider the repository came from, like `github/my-company`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// The topics or tags of the repository on the provider
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
 }
 
 impl Repository {
@@ -24,24 +394,29 @@ impl Repository {
         url: String,
         branch: Option<String>,
         upstream: Option<String>,
+        id: Option<String>,
+        topics: Vec<String>,
     ) -> Repository {
-        // We have to normalize repository names here. On windows if you do `path.join(self.name())`
-        // it will cause issues if the name contains a forward slash. So here we just normalize it
-        // to the path separator on the system.
-        let norm_path = if cfg!(windows) {
-            path.replace('/', std::path::MAIN_SEPARATOR.to_string().as_str())
-        } else {
-            path
-        };
-
         Repository {
-            path: norm_path,
+            path: normalize_path(path),
             url,
             branch,
             upstream,
+            id,
+            depth: None,
+            filter: None,
+            single_branch: false,
+            skip_fetch: false,
+            sparse: vec![],
+            provider: None,
+            topics,
         }
     }
 
+    pub fn set_path(&mut self, path: String) {
+        self.path = normalize_path(path);
+    }
+
     pub fn set_upstream(&self, root: &Path) -> anyhow::Result<()> {
         let upstream = match &self.upstream {
             Some(upstream) => upstream,
@@ -86,16 +461,21 @@ impl Repository {
         &self,
         command: &mut Command,
         progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
     ) -> anyhow::Result<()> {
         progress_bar.set_message(format!("{}: starting", self.name()));
+        isolate_for_timeout(command, timeout);
+        // Only stderr is read, so stdout is discarded rather than left to fill up its pipe.
         let mut spawned = command
             .stdin(Stdio::null())
-            .stdout(Stdio::piped())
+            .stdout(Stdio::null())
             .stderr(Stdio::piped())
             .spawn()
             .with_context(|| format!("Error starting command {:?}", command))?;
+        let watchdog = Watchdog::start(&spawned, timeout);
 
         let mut last_line = format!("{}: running...", self.name());
+        let mut transient = false;
         progress_bar.set_message(last_line.clone());
 
         if let Some(ref mut stderr) = spawned.stderr {
@@ -109,18 +489,28 @@ impl Repository {
                 let plain_line = strip_ansi_codes(line).replace('\n', " ");
                 let truncated_line = truncate_str(plain_line.trim(), 70, "...");
                 progress_bar.set_message(format!("{}: {}", self.name(), truncated_line));
+                transient |= TRANSIENT_ERRORS
+                    .iter()
+                    .any(|error| plain_line.contains(error));
                 last_line = plain_line;
             }
         }
         let exit_code = spawned
             .wait()
             .context("Error waiting for process to finish")?;
+        if watchdog.stop() {
+            return Err(CommandError::TimedOut(timeout.unwrap_or_default()).into());
+        }
         if !exit_code.success() {
-            return Err(anyhow!(
+            let message = format!(
                 "Git exited with code {}: {}",
                 exit_code.code().unwrap(),
                 last_line
-            ));
+            );
+            if transient {
+                return Err(CommandError::Transient(message).into());
+            }
+            return Err(anyhow!(message));
         }
         Ok(())
     }
@@ -129,18 +519,252 @@ impl Repository {
         &self,
         root: &Path,
         progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
         cmd: &str,
         args: &[String],
     ) -> anyhow::Result<()> {
         let mut command = Command::new(cmd);
         let child = command.args(args).current_dir(root.join(self.name()));
 
-        self.run_with_progress(child, progress_bar)
+        self.run_with_progress(child, progress_bar, timeout)
             .with_context(|| format!("Error running command in repo {}", self.name()))?;
 
         Ok(())
     }
 
+    /// Values describing the repository that `run` exposes to commands, both as `{name}`
+    /// placeholders and as `GW_*` environment variables.
+    fn command_values(&self, root: &Path) -> [(&'static str, &'static str, String); 5] {
+        [
+            (
+                "path",
+                "GW_REPO_PATH",
+                root.join(self.name()).display().to_string(),
+            ),
+            ("name", "GW_REPO_NAME", self.name().clone()),
+            ("url", "GW_REPO_URL", self.url.clone()),
+            (
+                "branch",
+                "GW_REPO_BRANCH",
+                self.branch.clone().unwrap_or_default(),
+            ),
+            (
+                "provider",
+                "GW_PROVIDER",
+                self.provider.clone().unwrap_or_default(),
+            ),
+        ]
+    }
+
+    /// Replace the `{path}`, `{name}`, `{url}`, `{branch}` and `{provider}` placeholders in
+    /// `text` with the values for this repository. Other text in braces is left alone.
+    ///
+    /// The values come from the provider, so in a shell snippet they are replaced with a
+    /// quoted reference to their environment variable instead, like `"$GW_REPO_BRANCH"`.
+    /// Pasting them in would let a branch name like `x;rm${IFS}-rf${IFS}~` run commands.
+    pub fn substitute_placeholders(&self, root: &Path, text: &str, shell: bool) -> String {
+        self.command_values(root).iter().fold(
+            text.to_string(),
+            |text, (placeholder, env_var, value)| {
+                let placeholder = format!("{{{}}}", placeholder);
+                if shell {
+                    text.replace(&placeholder, &format!("\"${}\"", env_var))
+                } else {
+                    text.replace(&placeholder, value)
+                }
+            },
+        )
+    }
+
+    /// Run a command in the repository, calling `on_line` with each line it writes to stdout or
+    /// stderr as they arrive. Returns the exit code, or None if the command was killed by a
+    /// signal. The command gets `GW_REPO_PATH`, `GW_REPO_NAME`, `GW_REPO_URL`,
+    /// `GW_REPO_BRANCH` and `GW_PROVIDER` environment variables describing the repository.
+    pub fn run_cmd<F>(
+        &self,
+        root: &Path,
+        timeout: Option<Duration>,
+        cmd: &str,
+        args: &[String],
+        mut on_line: F,
+    ) -> anyhow::Result<Option<i32>>
+    where
+        F: FnMut(&str),
+    {
+        let mut command = Command::new(cmd);
+        for (_, env_var, value) in self.command_values(root) {
+            command.env(env_var, value);
+        }
+        isolate_for_timeout(&mut command, timeout);
+        let mut spawned = command
+            .args(args)
+            .current_dir(root.join(self.name()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Error starting command {:?}", command))?;
+        let watchdog = Watchdog::start(&spawned, timeout);
+
+        // Read both streams on their own threads, so neither can fill up and block the command.
+        let (sender, receiver) = mpsc::channel();
+        let streams: [Option<Box<dyn Read + Send>>; 2] = [
+            spawned.stdout.take().map(|s| Box::new(s) as _),
+            spawned.stderr.take().map(|s| Box::new(s) as _),
+        ];
+        let readers: Vec<_> = streams
+            .into_iter()
+            .flatten()
+            .map(|stream| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for line in BufReader::new(stream).split(b'\n').map_while(Result::ok) {
+                        let line = String::from_utf8_lossy(&line);
+                        // Lines that redraw themselves, like progress bars, are shown the way a
+                        // terminal would show them once they are finished.
+                        let line = line.trim_end_matches('\r');
+                        let line = line.rsplit('\r').next().unwrap_or_default();
+                        if sender.send(line.to_string()).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(sender);
+
+        for line in receiver {
+            on_line(&line);
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+        let exit_status = spawned
+            .wait()
+            .context("Error waiting for process to finish")?;
+        if watchdog.stop() {
+            return Err(CommandError::TimedOut(timeout.unwrap_or_default()).into());
+        }
+        Ok(exit_status.code())
+    }
+
+    /// Fetch every remote of the repository, and of any submodules that are checked out, with
+    /// libgit2 rather than by running `git fetch`. Remote branches that no longer exist are
+    /// pruned.
+    #[cfg(feature = "native-fetch")]
+    pub fn fetch_native(
+        &self,
+        root: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let repo = Git2Repository::open(root.join(self.name()))
+            .with_context(|| format!("Error opening repo {}", self.name()))?;
+        fetch_remotes(&repo, self.name(), progress_bar, timeout, started)?;
+        for submodule in repo.submodules()? {
+            // Submodules that have not been checked out have nothing to fetch into
+            if let Ok(submodule_repo) = submodule.open() {
+                let name = format!("{}/{}", self.name(), submodule.path().display());
+                fetch_remotes(&submodule_repo, &name, progress_bar, timeout, started)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch what a shallow, partial or single branch clone left out, turning it into a full
+    /// clone. With `depth`, only that many more commits of a shallow clone are fetched instead.
+    pub fn deepen(
+        &self,
+        root: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+        depth: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let repo = Git2Repository::open(root.join(self.name()))?;
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+        let shallow = repo.is_shallow();
+        let mut args = vec!["fetch".to_string(), "--progress".to_string()];
+
+        if let Some(depth) = depth {
+            if !shallow {
+                return Ok(());
+            }
+            args.push(format!("--deepen={}", depth));
+        } else {
+            let partial = config
+                .get_string("remote.origin.partialclonefilter")
+                .is_ok();
+            let single_branch = !repo
+                .find_remote("origin")?
+                .fetch_refspecs()?
+                .iter()
+                .flatten()
+                .any(|refspec| refspec == ALL_BRANCHES_REFSPEC);
+            if !shallow && !partial && !single_branch {
+                return Ok(());
+            }
+            if single_branch {
+                repo.remote_add_fetch("origin", ALL_BRANCHES_REFSPEC)?;
+            }
+            if shallow {
+                args.push("--unshallow".to_string());
+            }
+            if partial {
+                // Without the filter, refetching downloads everything a full clone would
+                config.remove("remote.origin.partialclonefilter")?;
+                args.push("--refetch".to_string());
+            }
+        }
+        args.push("origin".to_string());
+
+        self.execute_cmd(root, progress_bar, timeout, "git", &args)
+    }
+
+    pub fn status(&self, root: &Path) -> anyhow::Result<RepositoryStatus> {
+        let mut repo = Git2Repository::open(root.join(self.name()))?;
+        let mut status = RepositoryStatus {
+            detached: repo.head_detached()?,
+            ..Default::default()
+        };
+
+        for entry in repo
+            .statuses(Some(
+                StatusOptions::new()
+                    .include_untracked(true)
+                    .exclude_submodules(true),
+            ))?
+            .iter()
+        {
+            if entry.status() == Status::WT_NEW {
+                status.untracked += 1;
+            } else if !entry.status().is_ignored() {
+                status.changed += 1;
+            }
+        }
+
+        // HEAD does not exist yet in an empty repository
+        if let Ok(head) = repo.head() {
+            if head.is_branch() {
+                status.branch = head.shorthand().map(str::to_string);
+                let upstream = repo
+                    .find_branch(head.shorthand().unwrap_or_default(), BranchType::Local)
+                    .and_then(|branch| branch.upstream());
+                if let (Some(local), Ok(upstream)) = (head.target(), upstream) {
+                    if let Some(upstream) = upstream.get().target() {
+                        (status.ahead, status.behind) = repo.graph_ahead_behind(local, upstream)?;
+                    }
+                }
+            }
+        }
+
+        repo.stash_foreach(|_, _, _| {
+            status.stashes += 1;
+            true
+        })?;
+        Ok(status)
+    }
+
     pub fn switch_to_primary_branch(&self, root: &Path) -> anyhow::Result<()> {
         let branch = match &self.branch {
             None => return Ok(()),
@@ -154,6 +778,16 @@ impl Repository {
                 branch
             ));
         }
+        // The branch may have been changed by an override after the repository was cloned, in
+        // which case we only have the remote branch to start from.
+        if repo.find_branch(branch, BranchType::Local).is_err() {
+            let remote = repo
+                .find_branch(&format!("origin/{}", branch), BranchType::Remote)
+                .with_context(|| format!("Cannot find branch {}", branch))?;
+            let commit = remote.get().peel_to_commit()?;
+            let mut local = repo.branch(branch, &commit, false)?;
+            local.set_upstream(Some(&format!("origin/{}", branch)))?;
+        }
         repo.set_head(&format!("refs/heads/{}", branch))
             .with_context(|| format!("Cannot find branch {}", branch))?;
         repo.checkout_head(Some(CheckoutBuilder::default().safe().force()))
@@ -161,30 +795,242 @@ impl Repository {
         Ok(())
     }
 
-    pub fn clone(&self, root: &Path, progress_bar: &ProgressBar) -> anyhow::Result<()> {
-        let mut command = Command::new("git");
+    pub fn clone(
+        &self,
+        root: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+        cache_dir: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        self.clone_with(Command::new("git"), root, progress_bar, timeout, cache_dir)
+    }
 
+    /// Clone the repository with `command`, a `git` command that may have its environment set
+    /// up already.
+    fn clone_with(
+        &self,
+        mut command: Command,
+        root: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+        cache_dir: Option<&Path>,
+    ) -> anyhow::Result<()> {
         let new_name = self.name();
-        let compatible_root_name = dunce::canonicalize(&root).unwrap();
-        let compatible_joined_name = compatible_root_name.join(&new_name);
+        let compatible_root_name = dunce::canonicalize(root).unwrap();
+        let compatible_joined_name = compatible_root_name.join(new_name);
 
         let child = command
             .arg("clone")
             .arg("--recurse-submodules")
-            .arg("--progress")
-            .arg(&self.url)
-            .arg(compatible_joined_name);
+            .arg("--progress");
+        if let Some(branch) = &self.branch {
+            child.arg("--branch").arg(branch);
+        }
+        if let Some(depth) = self.depth {
+            child.arg("--depth").arg(depth.to_string());
+        }
+        if let Some(filter) = self.filter {
+            child.arg(format!("--filter={}", filter));
+        }
+        if self.single_branch {
+            child.arg("--single-branch");
+        }
+        // Copy objects from the cached mirror, if there is one, and only download the rest.
+        // --dissociate means the clone keeps working if the cache is removed.
+        if let Some(cache_dir) = cache_dir {
+            child
+                .arg("--reference-if-able")
+                .arg(self.cache_path(cache_dir))
+                .arg("--dissociate");
+        }
+        // Only check out the files at the root, until the sparse checkout is set up below
+        if !self.sparse.is_empty() {
+            child.arg("--sparse");
+        }
+        child.arg(&self.url).arg(&compatible_joined_name);
 
-        self.run_with_progress(child, progress_bar)
-            .with_context(|| {
-                format!("Error cloning repo into {} from {}", self.name(), &self.url)
-            })?;
+        let existed = compatible_joined_name.exists();
+        let result = self
+            .run_with_progress(child, progress_bar, timeout)
+            .with_context(|| format!("Error cloning repo into {} from {}", self.name(), &self.url));
+        // A clone that was killed by a timeout leaves a half-created checkout behind, which
+        // `exists` would treat as cloned. Remove it so the next update clones it again.
+        if let Err(e) = result {
+            if existed || !compatible_joined_name.exists() {
+                return Err(e);
+            }
+            return match fs::remove_dir_all(&compatible_joined_name) {
+                Ok(_) => Err(e),
+                Err(remove_error) => Err(e.context(format!(
+                    "Error removing the incomplete clone in {}: {}",
+                    compatible_joined_name.display(),
+                    remove_error
+                ))),
+            };
+        }
 
+        // The clone is kept if this fails, as cloning it again would not help
+        if !self.sparse.is_empty() {
+            self.sync_sparse_checkout(root, progress_bar, timeout)
+                .with_context(|| {
+                    format!(
+                        "Cloned {} but could not set up its sparse checkout, run `git workspace \
+                         sparse sync` to try again",
+                        self.name()
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Where the bare mirror of this repository is kept in a clone cache, like
+    /// `<cache>/github.com/owner/name.git`. Mirrors are keyed by URL rather than path, so every
+    /// workspace sharing the cache uses the same mirror for a repository.
+    pub fn cache_path(&self, cache_dir: &Path) -> PathBuf {
+        let without_scheme = match self.url.split_once("://") {
+            Some((_, rest)) => rest,
+            None => &self.url,
+        };
+        // `git@host:owner/name` and `ssh://git@host/owner/name` share a mirror
+        let without_user = match without_scheme.split_once('@') {
+            Some((user, rest)) if !user.contains('/') => rest,
+            _ => without_scheme,
+        };
+        let components: Vec<&str> = without_user
+            .split(['/', ':'])
+            .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+            .collect();
+        let mut path = cache_dir.to_path_buf();
+        if let Some((name, parents)) = components.split_last() {
+            path.extend(parents);
+            path.push(format!("{}.git", name.trim_end_matches(".git")));
+        }
+        path
+    }
+
+    /// Clone a bare mirror of this repository into a clone cache, or fetch new commits into the
+    /// mirror if it already exists.
+    pub fn update_cache(
+        &self,
+        cache_dir: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let mirror = self.cache_path(cache_dir);
+        let mut command = Command::new("git");
+        if mirror.exists() {
+            command
+                .current_dir(&mirror)
+                .args(["fetch", "--prune", "--progress", "origin"]);
+        } else {
+            if let Some(parent) = mirror.parent() {
+                fs_extra::dir::create_all(parent, false)
+                    .with_context(|| format!("Error creating directory {}", parent.display()))?;
+            }
+            command
+                .args(["clone", "--mirror", "--progress"])
+                .arg(&self.url)
+                .arg(&mirror);
+        }
+        self.run_with_progress(&mut command, progress_bar, timeout)
+            .with_context(|| format!("Error updating the mirror in {}", mirror.display()))
+    }
+
+    /// The directories in the sparse checkout of the repository, or None if it is a full
+    /// checkout.
+    fn sparse_checkout(&self, root: &Path) -> anyhow::Result<Option<Vec<String>>> {
+        // git keeps this in the worktree config, which libgit2 doesn't read
+        let enabled = self.git_output(root, &["config", "--bool", "core.sparseCheckout"])?;
+        if enabled.status.code() == Some(1) || enabled.stdout.trim_ascii() == b"false" {
+            return Ok(None);
+        }
+        let output = self.git_output(root, &["sparse-checkout", "list"])?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Error listing the sparse checkout of {}: {}",
+                self.name(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let mut directories: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        directories.sort();
+        Ok(Some(directories))
+    }
+
+    /// Run a quick git command in the repository and capture its output.
+    fn git_output(&self, root: &Path, args: &[&str]) -> anyhow::Result<Output> {
+        Command::new("git")
+            .args(args)
+            .current_dir(root.join(self.name()))
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Error running git {}", args.join(" ")))
+    }
+
+    /// Make the checkout match `sparse`: a cone mode sparse checkout of those directories, or a
+    /// full checkout if there are none. Returns whether anything changed.
+    pub fn sync_sparse_checkout(
+        &self,
+        root: &Path,
+        progress_bar: &ProgressBar,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let mut wanted: Vec<String> = self
+            .sparse
+            .iter()
+            .map(|directory| directory.trim_matches('/').to_string())
+            .collect();
+        wanted.sort();
+        let args: Vec<String> = match self.sparse_checkout(root)? {
+            None if wanted.is_empty() => return Ok(false),
+            Some(current) if current == wanted => return Ok(false),
+            Some(_) if wanted.is_empty() => vec!["sparse-checkout".into(), "disable".into()],
+            _ => ["sparse-checkout", "set", "--cone"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .chain(wanted)
+                .collect(),
+        };
+        self.execute_cmd(root, progress_bar, timeout, "git", &args)?;
+        Ok(true)
+    }
+    /// Move an existing checkout of this repository from `previous_name`, and point its origin
+    /// at our URL. This is used when a repository is renamed or transferred on the provider.
+    pub fn move_from(&self, root: &Path, previous_name: &str) -> anyhow::Result<()> {
+        let from = root.join(previous_name);
+        let to = root.join(self.name());
+        if let Some(parent) = to.parent() {
+            fs_extra::dir::create_all(parent, false)
+                .with_context(|| format!("Error creating directory {}", parent.display()))?;
+        }
+        if to.exists() {
+            // On a case-insensitive filesystem a rename that only changes the case of the path
+            // will find the existing checkout. Renaming it directly would be a no-op, so we
+            // move it to a temporary path first.
+            if from.canonicalize()? != to.canonicalize()? {
+                return Err(anyhow!("{} already exists", to.display()));
+            }
+            let temp = root.join(format!("{}.renaming", previous_name));
+            fs::rename(&from, &temp).with_context(|| format!("Error moving {}", from.display()))?;
+            fs::rename(&temp, &to).with_context(|| format!("Error moving {}", temp.display()))?;
+        } else {
+            fs::rename(&from, &to).with_context(|| format!("Error moving {}", from.display()))?;
+        }
+
+        let repo = Git2Repository::open(&to)?;
+        repo.remote_set_url("origin", &self.url)
+            .with_context(|| format!("Error setting the origin of {}", self.name()))?;
         Ok(())
     }
     pub fn name(&self) -> &String {
         &self.path
     }
+    pub fn url(&self) -> &str {
+        &self.url
+    }
     pub fn get_path(&self, root: &Path) -> anyhow::Result<PathBuf> {
         let joined = root.join(self.name());
         joined
@@ -201,3 +1047,85 @@ impl Repository {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn clone_removes_checkout_after_timeout() {
+        let root = std::env::temp_dir().join(format!("git-workspace-clone-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let repository = Repository::new(
+            "hung".to_string(),
+            "ssh://git@example.com/owner/hung.git".to_string(),
+            None,
+            None,
+            None,
+            vec![],
+        );
+
+        // An SSH connection that never answers, like a hung host
+        let mut command = Command::new("git");
+        command.env("GIT_SSH_COMMAND", "sleep 30; true");
+        let result = repository.clone_with(
+            command,
+            &root,
+            &ProgressBar::hidden(),
+            Some(Duration::from_secs(1)),
+            None,
+        );
+        let leftover = root.join("hung").exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::TimedOut(_))
+        ));
+        assert!(!leftover, "the incomplete clone was left behind");
+    }
+
+    #[test]
+    fn clone_is_kept_when_the_sparse_checkout_fails() {
+        let root =
+            std::env::temp_dir().join(format!("git-workspace-sparse-{}", std::process::id()));
+        let upstream = root.join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "initial"]);
+
+        let mut repository = Repository::new(
+            "checkout".to_string(),
+            upstream.display().to_string(),
+            None,
+            None,
+            None,
+            vec![],
+        );
+        // Not a valid directory for `git sparse-checkout set`
+        repository.sparse = vec!["../outside".to_string()];
+
+        let result = repository.clone(&root, &ProgressBar::hidden(), None, None);
+        let kept = root.join("checkout").join(".git").exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.contains("could not set up its sparse checkout"),
+            "{}",
+            error
+        );
+        assert!(kept, "the clone was removed");
+    }
+}
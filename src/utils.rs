@@ -1,19 +1,34 @@
+use anyhow::Context;
+use console::style;
+use similar::{ChangeTag, TextDiff};
 use std::io;
 use std::io::Write;
+use std::path::Path;
 
 // From https://docs.rs/clt/latest/src/clt/term.rs.html#277-293
 
-fn get_prompt_input(prompt_text: &str) -> String {
+fn get_prompt_input(prompt_text: &str) -> anyhow::Result<String> {
     print!("{}", prompt_text);
     io::stdout().flush().unwrap();
     let mut input = String::new();
-    io::stdin()
+    let read = io::stdin()
         .read_line(&mut input)
-        .expect("Failed to read line");
-    return input.trim_end_matches('\n').to_string();
+        .context("Failed to read line")?;
+    // Nothing was read at the end of the input, like when stdin is closed or not a terminal.
+    // Asking again would loop forever.
+    if read == 0 {
+        println!();
+        anyhow::bail!("No input was given, stdin is closed");
+    }
+    Ok(input.trim_end_matches('\n').to_string())
 }
 
-pub fn confirm(text: &str, default: bool, prompt_suffix: &str, show_default: bool) -> bool {
+pub fn confirm(
+    text: &str,
+    default: bool,
+    prompt_suffix: &str,
+    show_default: bool,
+) -> anyhow::Result<bool> {
     let default_string = match default {
         true => Some("Y/n"),
         false => Some("y/N"),
@@ -21,16 +36,16 @@ pub fn confirm(text: &str, default: bool, prompt_suffix: &str, show_default: boo
     let prompt_text = build_prompt_text(text, prompt_suffix, show_default, default_string);
 
     loop {
-        let prompt_input = get_prompt_input(&prompt_text).to_ascii_lowercase();
+        let prompt_input = get_prompt_input(&prompt_text)?.to_ascii_lowercase();
         match prompt_input.trim() {
             "y" | "yes" => {
-                return true;
+                return Ok(true);
             }
             "n" | "no" => {
-                return false;
+                return Ok(false);
             }
             "" => {
-                return default;
+                return Ok(default);
             }
             _ => {
                 println!("Error: invalid input");
@@ -39,16 +54,54 @@ pub fn confirm(text: &str, default: bool, prompt_suffix: &str, show_default: boo
     }
 }
 
+/// Ask for a value, using `default` if nothing is entered. Without a default a value is required.
+pub fn prompt(text: &str, default: Option<&str>, prompt_suffix: &str) -> anyhow::Result<String> {
+    let prompt_text = build_prompt_text(text, prompt_suffix, true, default);
+
+    loop {
+        let prompt_input = get_prompt_input(&prompt_text)?;
+        match (prompt_input.trim(), default) {
+            ("", Some(default)) => {
+                return Ok(default.to_string());
+            }
+            ("", None) => {
+                println!("Error: a value is required");
+            }
+            (value, _) => {
+                return Ok(value.to_string());
+            }
+        }
+    }
+}
+
 fn build_prompt_text(
     text: &str,
     suffix: &str,
     show_default: bool,
     default: Option<&str>,
 ) -> String {
-    let prompt_text = if default.is_some() && show_default {
-        format!("{} [{}]", text, default.unwrap())
-    } else {
-        text.to_string()
+    let prompt_text = match default {
+        Some(default) if show_default => format!("{} [{}]", text, default),
+        _ => text.to_string(),
     };
     prompt_text + suffix
 }
+
+/// Print a unified diff between two versions of a file.
+pub fn print_diff(path: &Path, before: &str, after: &str) {
+    let file_name = path.display().to_string();
+    let diff = TextDiff::from_lines(before, after);
+    println!("{}", style(format!("--- {}", file_name)).bold());
+    println!("{}", style(format!("+++ {}", file_name)).bold());
+    for hunk in diff.unified_diff().iter_hunks() {
+        println!("{}", style(hunk.header()).cyan());
+        for change in hunk.iter_changes() {
+            let line = format!("{}{}", change.tag(), change.value().trim_end_matches('\n'));
+            match change.tag() {
+                ChangeTag::Delete => println!("{}", style(line).red()),
+                ChangeTag::Insert => println!("{}", style(line).green()),
+                ChangeTag::Equal => println!("{}", line),
+            }
+        }
+    }
+}
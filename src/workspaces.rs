@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// The user-level configuration, which lists the workspaces on this machine by name. This is
+/// separate from the configuration files inside each workspace.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// The workspace to use if none is given on the command line
+    pub default: Option<String>,
+    /// The root directory of each workspace, by name
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, PathBuf>,
+}
+
+impl UserConfig {
+    /// The location of the user config file, `$XDG_CONFIG_HOME/git-workspace/config.toml`.
+    /// This falls back to `~/.config`, or `%APPDATA%` on Windows.
+    pub fn path() -> Option<PathBuf> {
+        let config_directory = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_directory.join("git-workspace").join("config.toml"))
+    }
+
+    /// Read the user config file. It is fine for it not to exist.
+    pub fn read() -> anyhow::Result<UserConfig> {
+        let path = match UserConfig::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(UserConfig::default()),
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Error parsing TOML in file {}", path.display()))
+    }
+
+    /// Find the root directory of the workspace called `name`.
+    pub fn workspace(&self, name: &str) -> anyhow::Result<&PathBuf> {
+        self.workspaces.get(name).ok_or_else(|| {
+            let path = UserConfig::path().unwrap_or_default();
+            if self.workspaces.is_empty() {
+                anyhow!(
+                    "Unknown workspace {}, no workspaces are configured in {}",
+                    name,
+                    path.display()
+                )
+            } else {
+                anyhow!(
+                    "Unknown workspace {}, expected one of {}",
+                    name,
+                    self.workspaces
+                        .keys()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        })
+    }
+}